@@ -0,0 +1,196 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use log::{info, warn};
+use serde::{Serialize, de::DeserializeOwned};
+use reqwest::{Client, StatusCode};
+use oauth2::{AuthUrl,ClientId,ClientSecret,TokenResponse,TokenUrl, StandardTokenResponse, EmptyExtraTokenFields, RequestTokenError};
+use oauth2::basic::{BasicClient, BasicTokenType};
+use oauth2::reqwest::async_http_client;
+use graphql_client::{GraphQLQuery, Response};
+use tokio::sync::Mutex;
+use tokio::time::sleep;
+use crate::updater::{UpdateError, ClassesView, classes_view, RateLimitView, rate_limit_view, ZonesView, zones_view};
+
+const AUTH_TOKEN_LIFETIME: u64 = 3600;          // Assumed if the token response has no expiry
+const AUTH_TOKEN_MARGIN: u64 = 300;             // Refresh tokens 5 minutes before they expire
+const REQUEST_TIMEOUT: u64 = 30;                // Seconds until a request is considered timed out
+pub const RETRY_MAX: u32 = 3;                   // Retries of transient failures per request
+pub const RETRY_BASE_MS: u64 = 500;             // Backoff before the first retry, doubled each attempt
+
+struct WclToken {
+    bearer: String,
+    expires: SystemTime
+}
+
+/// Handle to the WarcraftLogs API of one site. Clones share the access token, so
+/// requests can be sent concurrently without holding the updater lock.
+#[derive(Clone)]
+pub struct WclApi {
+    http_client: Client,
+    base_url: String,
+    api_id: String,
+    api_secret: String,
+    retry_max: u32,
+    retry_base_ms: u64,
+    token: Arc<Mutex<WclToken>>
+}
+
+impl WclApi {
+    pub fn new(base_url: &str, api_id: &str, api_secret: &str, retry_max: u32, retry_base_ms: u64) -> WclApi {
+        WclApi{
+            http_client: Client::builder().user_agent("graphql-rust/0.10.0").timeout(Duration::new(REQUEST_TIMEOUT, 0)).build().unwrap(),
+            base_url: base_url.to_string(),
+            api_id: api_id.to_string(),
+            api_secret: api_secret.to_string(),
+            retry_max,
+            retry_base_ms,
+            token: Arc::new(Mutex::new(WclToken{ bearer: String::new(), expires: SystemTime::UNIX_EPOCH }))
+        }
+    }
+
+    /// Returns the authorization header, requesting a new token if the current one is about
+    /// to expire. Concurrent callers wait for a single token request. Only a rejection by the
+    /// server is reported as authentication failure, other failures are retried.
+    async fn auth(&self) -> Result<String, UpdateError> {
+        let mut token = self.token.lock().await;
+        if !token.bearer.is_empty() && (SystemTime::now() + Duration::new(AUTH_TOKEN_MARGIN, 0) < token.expires) {
+            return Ok(token.bearer.clone());
+        }
+        let client = BasicClient::new(
+            ClientId::new(self.api_id.clone()),
+            Some(ClientSecret::new(self.api_secret.clone())),
+            AuthUrl::new(format!("{}/oauth/authorize", self.base_url)).unwrap(),
+            Some(TokenUrl::new(format!("{}/oauth/token", self.base_url)).unwrap()),
+        );
+        let mut retry_count = 0;
+        loop {
+            let token_result: Result<StandardTokenResponse<EmptyExtraTokenFields, BasicTokenType>, _> = client
+                .exchange_client_credentials()
+                .request_async(async_http_client)
+                .await;
+            let error = match token_result {
+                Ok(token_result) => {
+                    token.bearer = format!("Bearer {}", token_result.access_token().secret());
+                    token.expires = SystemTime::now() + token_result.expires_in().unwrap_or(Duration::new(AUTH_TOKEN_LIFETIME, 0));
+                    return Ok(token.bearer.clone());
+                },
+                Err(RequestTokenError::ServerResponse(response)) => UpdateError::Auth(response.to_string()),
+                Err(e) if retry_count < self.retry_max => {
+                    warn!("Token request failed: {e}, retrying");
+                    sleep(self.retry_delay(retry_count)).await;
+                    retry_count += 1;
+                    continue;
+                },
+                Err(e) => UpdateError::Network(e.to_string())
+            };
+            warn!("{error}");
+            token.bearer.clear();
+            return Err(error);
+        }
+    }
+
+    /// Drops the given token, unless another request already replaced it.
+    async fn invalidate(&self, bearer: &str) {
+        let mut token = self.token.lock().await;
+        if token.bearer == bearer {
+            token.bearer.clear();
+        }
+    }
+
+    /// Delay before the given retry, doubled for every attempt and randomized by up to 50%
+    /// so multiple clients don't hit the API in lockstep.
+    fn retry_delay(&self, attempt: u32) -> Duration {
+        let delay_ms = self.retry_base_ms.saturating_mul(1 << attempt.min(16));
+        let jitter_seed = u64::from(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().subsec_nanos());
+        let jitter_ms = match delay_ms / 2 {
+            0 => 0,
+            jitter_max => jitter_seed % jitter_max
+        };
+        Duration::from_millis(delay_ms + jitter_ms)
+    }
+
+    async fn send_query<T: DeserializeOwned>(&self, body: &impl Serialize) -> Result<Response<T>, UpdateError> {
+        let mut retry_count = 0;
+        let mut retry_auth = true;
+        loop {
+            let bearer = self.auth().await?;
+            let response = self.http_client.post(format!("{}/api/v2/client", self.base_url))
+                .header(reqwest::header::AUTHORIZATION, bearer.as_str())
+                .json(body)
+                .send()
+                .await;
+            let response = match response {
+                Ok(response) if response.status().is_server_error() && (retry_count < self.retry_max) => {
+                    warn!("Server error {}, retrying", response.status());
+                    sleep(self.retry_delay(retry_count)).await;
+                    retry_count += 1;
+                    continue;
+                },
+                Ok(response) => response,
+                Err(e) if (e.is_timeout() || e.is_connect() || e.is_request()) && (retry_count < self.retry_max) => {
+                    warn!("Request failed: {e}, retrying");
+                    sleep(self.retry_delay(retry_count)).await;
+                    retry_count += 1;
+                    continue;
+                },
+                Err(e) => {
+                    return Err(UpdateError::Network(e.to_string()));
+                }
+            };
+            match response.status() {
+                StatusCode::UNAUTHORIZED if retry_auth => {
+                    // Token got revoked or expired early, request a new one and try again
+                    info!("Access token rejected, requesting a new one");
+                    self.invalidate(&bearer).await;
+                    retry_auth = false;
+                },
+                StatusCode::UNAUTHORIZED => {
+                    let error = UpdateError::Auth("Access token rejected".to_string());
+                    warn!("{error}");
+                    self.invalidate(&bearer).await;
+                    return Err(error);
+                },
+                StatusCode::TOO_MANY_REQUESTS => {
+                    return Err(UpdateError::RateLimited(None));
+                },
+                status if status.is_client_error() => {
+                    // Sending the same request again won't help
+                    return Err(UpdateError::Rejected(format!("Response status {}", status)));
+                },
+                status if !status.is_success() => {
+                    return Err(UpdateError::Network(format!("Unexpected response status {}", status)));
+                },
+                _ => {
+                    return response.json::<Response<T>>().await.map_err(|e| UpdateError::Parse(e.to_string()));
+                }
+            }
+        }
+    }
+
+    /// Sends a document built at runtime, `operation_name` picks the query of the document to run
+    pub async fn query_document(&self, operation_name: &str, document: &str) -> Result<Response<serde_json::Value>, UpdateError> {
+        let body = serde_json::json!({
+            "query": document,
+            "operationName": operation_name
+        });
+        self.send_query::<serde_json::Value>(&body).await
+    }
+
+    pub async fn query_zones(&self) -> Result<zones_view::ResponseData, UpdateError> {
+        let body = ZonesView::build_query(zones_view::Variables {});
+        self.send_query::<zones_view::ResponseData>(&body).await?.data
+            .ok_or_else(|| UpdateError::Parse("Missing zone data".to_string()))
+    }
+
+    pub async fn query_classes(&self) -> Result<classes_view::ResponseData, UpdateError> {
+        let body = ClassesView::build_query(classes_view::Variables {});
+        self.send_query::<classes_view::ResponseData>(&body).await?.data
+            .ok_or_else(|| UpdateError::Parse("Missing class data".to_string()))
+    }
+
+    pub async fn query_rate_limit(&self) -> Result<rate_limit_view::ResponseData, UpdateError> {
+        let body = RateLimitView::build_query(rate_limit_view::Variables {});
+        self.send_query::<rate_limit_view::ResponseData>(&body).await?.data
+            .ok_or_else(|| UpdateError::Parse("Missing rate limit data".to_string()))
+    }
+}
@@ -0,0 +1,270 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use log::warn;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::time::sleep;
+use crate::updater::{UpdateError, Updater, UpdaterPlayer, UpdaterRequest};
+
+pub async fn update_api_limit(updater: &Arc<Mutex<Updater>>) -> Result<(), UpdateError> {
+    let api = updater.lock().unwrap().api();
+    let rate_limit_response = api.query_rate_limit().await;
+    updater.lock().unwrap().apply_api_limit(rate_limit_response)
+}
+
+pub async fn update_zones(updater: &Arc<Mutex<Updater>>) -> Result<(), UpdateError> {
+    let api = {
+        let updater_locked = updater.lock().unwrap();
+        if !updater_locked.is_zone_update_due() {
+            return Ok(());
+        }
+        updater_locked.api()
+    };
+    let zones_response = api.query_zones().await;
+    updater.lock().unwrap().apply_zones(zones_response)
+}
+
+pub async fn update_guilds(updater: &Arc<Mutex<Updater>>) -> Result<(), UpdateError> {
+    let Some(request) = updater.lock().unwrap().prepare_guilds() else {
+        return Ok(());
+    };
+    let guilds_response = request.send().await;
+    updater.lock().unwrap().apply_guilds(request, guilds_response)
+}
+
+/// Pages through the roster of a guild and queues every member, returns the number of members queued
+pub async fn queue_guild(updater: &Arc<Mutex<Updater>>, realm: &str, guild_name: &str, priority: i64) -> Result<usize, UpdateError> {
+    let realm_unknown = updater.lock().unwrap().realm_lookup(realm).is_none();
+    if realm_unknown {
+        // Reported as unknown realm below if the lookup fails
+        if let Err(e) = resolve_realm(updater, realm).await {
+            warn!("Failed to look up realm {realm}: {e}");
+        }
+    }
+    let mut queued_count = 0;
+    let mut page = 1;
+    loop {
+        let (api, document) = updater.lock().unwrap().prepare_guild_members(realm, guild_name, page)?;
+        let members_response = api.query_document("GuildMembersView", &document).await;
+        let (page_count, more_pages) = updater.lock().unwrap().apply_guild_members(realm, guild_name, priority, page, members_response)?;
+        queued_count += page_count;
+        if !more_pages {
+            return Ok(queued_count);
+        }
+        page += 1;
+    }
+}
+
+pub async fn update_classes(updater: &Arc<Mutex<Updater>>) -> Result<(), UpdateError> {
+    let api = {
+        let updater_locked = updater.lock().unwrap();
+        if !updater_locked.is_classes_update_due() {
+            return Ok(());
+        }
+        updater_locked.api()
+    };
+    let classes_response = api.query_classes().await;
+    updater.lock().unwrap().apply_classes(classes_response)
+}
+
+async fn send_request(updater: &Arc<Mutex<Updater>>, request: UpdaterRequest) -> Result<Vec<Result<(), UpdateError>>, UpdateError> {
+    let mut points_before = None;
+    if request.is_cost_sample() && update_api_limit(updater).await.is_ok() {
+        points_before = Some(updater.lock().unwrap().points_used());
+    }
+    let response = if request.is_empty() {
+        Err(UpdateError::Parse("Empty request".to_string()))
+    } else {
+        request.send().await
+    };
+    if let Some(points_before) = points_before {
+        if response.is_ok() && update_api_limit(updater).await.is_ok() {
+            updater.lock().unwrap().record_request_cost(&request, points_before);
+        }
+    }
+    updater.lock().unwrap().apply_request(request, response)
+}
+
+/// Looks up the region and slug of a realm missing from the base data
+pub async fn resolve_realm(updater: &Arc<Mutex<Updater>>, realm: &str) -> Result<(), UpdateError> {
+    let mut page = 0;
+    loop {
+        let (api, document) = updater.lock().unwrap().prepare_realm_lookup(realm, page);
+        let realm_response = api.query_document("RealmView", &document).await;
+        if !updater.lock().unwrap().apply_realm_lookup(realm, page, realm_response)? {
+            return Ok(());
+        }
+        page += 1;
+    }
+}
+
+pub async fn update_players(updater: &Arc<Mutex<Updater>>, players: Vec<UpdaterPlayer>) -> Result<Vec<Result<(), UpdateError>>, UpdateError> {
+    if let Err(e) = update_zones(updater).await {
+        warn!("Failed to update the zone list: {e}");
+    }
+    if let Err(e) = update_classes(updater).await {
+        warn!("Failed to update the class list: {e}");
+    }
+    let mut unknown_realms: Vec<String> = {
+        let updater_locked = updater.lock().unwrap();
+        players.iter().map(|player| player.realm().to_string()).filter(|realm| updater_locked.realm_lookup(realm).is_none()).collect()
+    };
+    unknown_realms.sort();
+    unknown_realms.dedup();
+    for realm in unknown_realms {
+        // Players of realms that are still unknown are reported by the request
+        if let Err(e) = resolve_realm(updater, &realm).await {
+            warn!("Failed to look up realm {realm}: {e}");
+        }
+    }
+    let request = updater.lock().unwrap().prepare_request(players);
+    send_request(updater, request).await
+}
+
+pub async fn update_player(updater: &Arc<Mutex<Updater>>, player: UpdaterPlayer) -> Result<(), UpdateError> {
+    update_players(updater, vec![player]).await?.pop().unwrap()
+}
+
+/// Only reports player errors if nobody in the batch could be updated
+fn batch_result(player_results: Vec<Result<(), UpdateError>>) -> Result<(), UpdateError> {
+    let player_count = player_results.len();
+    let player_errors: Vec<UpdateError> = player_results.into_iter().filter_map(|result| result.err()).collect();
+    match player_errors.first() {
+        Some(player_error) if player_errors.len() == player_count => Err(player_error.clone()),
+        _ => Ok(())
+    }
+}
+
+fn pause_after(result: &Result<(), UpdateError>) -> Option<SystemTime> {
+    match result {
+        // Player specific errors do not affect the remaining queue
+        Ok(()) | Err(UpdateError::UnknownRealm(_)) | Err(UpdateError::CharacterNotFound(_)) | Err(UpdateError::GuildNotFound(_)) |
+        Err(UpdateError::UnknownClass(_)) | Err(UpdateError::Rejected(_)) | Err(UpdateError::Parse(_)) => None,
+        Err(UpdateError::RateLimited(resume_at)) => {
+            Some(resume_at.unwrap_or_else(|| SystemTime::now() + Duration::new(60, 0)))
+        },
+        Err(UpdateError::Auth(_)) => Some(SystemTime::now() + Duration::new(300, 0)),
+        Err(UpdateError::Network(_)) => Some(SystemTime::now() + Duration::new(30, 0))
+    }
+}
+
+/// Works through the update queue until the updater is stopped, keeping up to
+/// `max_in_flight` character requests running at the same time.
+pub async fn run(updater: Arc<Mutex<Updater>>) {
+    sleep(Duration::new(1, 0)).await;
+    let max_in_flight = updater.lock().unwrap().max_in_flight();
+    let request_slots = Arc::new(Semaphore::new(max_in_flight));
+    let (result_sender, mut result_receiver) = mpsc::unbounded_channel::<Result<(), UpdateError>>();
+    let mut last_rate_update = SystemTime::now() - Duration::new(30, 0);
+    let mut last_export = SystemTime::now();
+    let mut last_update = SystemTime::now();
+    let mut pause_until = SystemTime::now();
+    let mut guilds_paused_until = SystemTime::now();
+    loop {
+        while let Ok(result) = result_receiver.try_recv() {
+            last_update = SystemTime::now();
+            if let Some(resume_at) = pause_after(&result) {
+                pause_until = pause_until.max(resume_at);
+            }
+        }
+        let (active, update_possible) = {
+            let mut updater_locked = updater.lock().unwrap();
+            if updater_locked.is_active() && (updater_locked.requests_in_flight() == 0) {
+                // Rebuilding the queue would request the players in flight again
+                updater_locked.update_addon();
+            }
+            (updater_locked.is_active(), updater_locked.is_update_possible())
+        };
+        if !active {
+            break;
+        }
+        if !update_possible {
+            {
+                let mut updater_locked = updater.lock().unwrap();
+                if updater_locked.requests_in_flight() == 0 {
+                    updater_locked.set_status_text("\nUpdate completed.");
+                    updater_locked.update_gui();
+                    let last_update_secs = SystemTime::now().duration_since(last_update).unwrap_or_default().as_secs();
+                    if last_update_secs > 300 {
+                        // Finished 5 minutes ago, check for due updates again
+                        updater_locked.rewrite_update_queue();
+                        last_update = SystemTime::now();
+                    }
+                }
+            }
+            sleep(Duration::new(1, 0)).await;
+            continue;
+        }
+        if pause_until > SystemTime::now() {
+            sleep(Duration::new(1, 0)).await;
+            continue;
+        }
+        let last_rate_update_secs = SystemTime::now().duration_since(last_rate_update).unwrap_or_default().as_secs();
+        if last_rate_update_secs > 15 {
+            if let Err(e) = update_api_limit(&updater).await {
+                warn!("Failed to update the rate limit: {e}");
+            }
+            last_rate_update = SystemTime::now();
+        }
+        if let Err(e) = update_zones(&updater).await {
+            warn!("Failed to update the zone list: {e}");
+            if updater.lock().unwrap().selected_zones().is_empty() {
+                // Nothing can be ranked without the zone sizes, try again later
+                pause_until = pause_until.max(pause_after(&Err(e)).unwrap_or_else(|| SystemTime::now() + Duration::new(30, 0)));
+                continue;
+            }
+        }
+        if let Err(e) = update_classes(&updater).await {
+            warn!("Failed to update the class list: {e}");
+        }
+        let unresolved_realm = updater.lock().unwrap().next_unresolved_realm();
+        if let Some(realm) = unresolved_realm {
+            if let Err(e) = resolve_realm(&updater, &realm).await {
+                warn!("Failed to look up realm {realm}: {e}");
+                if let Some(resume_at) = pause_after(&Err(e)) {
+                    pause_until = pause_until.max(resume_at);
+                    continue;
+                }
+            }
+        }
+        if guilds_paused_until <= SystemTime::now() {
+            if let Err(e) = update_guilds(&updater).await {
+                // Only the guild progress waits, the characters keep updating
+                warn!("Failed to update the guild progress: {e}");
+                guilds_paused_until = pause_after(&Err(e)).unwrap_or_else(|| SystemTime::now() + Duration::new(30, 0));
+            }
+        }
+        let last_export_secs = SystemTime::now().duration_since(last_export).unwrap_or_default().as_secs();
+        if last_export_secs > 30 {
+            updater.lock().unwrap().write_addon_data();
+            last_export = SystemTime::now();
+        }
+        let request_slot = request_slots.clone().acquire_owned().await.unwrap();
+        let request = updater.lock().unwrap().prepare_next();
+        match request {
+            Ok(Some(request)) => {
+                let updater_task = updater.clone();
+                let result_sender = result_sender.clone();
+                tokio::spawn(async move {
+                    let result = send_request(&updater_task, request).await.and_then(batch_result);
+                    updater_task.lock().unwrap().report_batch(&result);
+                    result_sender.send(result).ok();
+                    drop(request_slot);
+                });
+            },
+            Ok(None) => {
+                // Wait for the requests in flight to settle the points spent
+                drop(request_slot);
+                sleep(Duration::from_millis(100)).await;
+            },
+            Err(e) => {
+                drop(request_slot);
+                if let Some(resume_at) = pause_after(&Err(e)) {
+                    pause_until = resume_at;
+                }
+            }
+        }
+    }
+    // Let running requests finish before the final export
+    let _request_slots = request_slots.acquire_many(max_in_flight as u32).await;
+    updater.lock().unwrap().write_addon_data();
+}
@@ -1,22 +1,28 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")] // hide console window on Windows in release
 
+mod api;
+mod engine;
+#[cfg(test)]
+mod mock;
+mod query;
+mod slug;
 mod updater;
 
-use image;
 use eframe::egui;
 use tinyfiledialogs::MessageBoxIcon;
-use std::time::{SystemTime, Duration};
-use updater::{Updater, UpdaterGuiData};
+use updater::{Updater, UpdaterGuiData, UpdaterPartitionMode, UpdaterSite, UpdaterTimeframe};
 use std::sync::{Arc, Mutex};
 use std::path::PathBuf;
-use std::thread::{self, JoinHandle};
+use tokio::runtime::Runtime;
+use tokio::task::JoinHandle;
 
 const ICON: &[u8] = include_bytes!("../LogTracker.png");
 
 struct LogTrackerApp {
     gui_data: Arc<Mutex<UpdaterGuiData>>,
     updater_arc: Arc<Mutex<Updater>>,
-    updater_thread: Option<JoinHandle<()>>
+    updater_runtime: Runtime,
+    updater_task: Option<JoinHandle<()>>
 }
 
 impl LogTrackerApp {
@@ -28,69 +34,20 @@ impl LogTrackerApp {
                 ..Default::default()
             })),
             updater_arc: Arc::new(Mutex::new(Updater::new())),
-            updater_thread: None
+            updater_runtime: Runtime::new().expect("Failed to start the update runtime"),
+            updater_task: None
         }
     }
 
-    pub fn start_update_thread(&mut self) {
+    pub fn start_updater(&mut self) {
         {
             let updater = &mut self.updater_arc.lock().unwrap();
             updater.set_gui_data(self.gui_data.clone());
             updater.load_config();
+            updater.load_cache();
             updater.read_addon_data();
         }
-        let updater_thread = self.updater_arc.clone();
-        let gui_data  = self.gui_data.clone();
-        self.updater_thread = Some(thread::spawn(move || {
-            thread::sleep(Duration::new(1, 0));
-            let mut last_rate_update = SystemTime::now() - Duration::new(30, 0);
-            let mut last_export = SystemTime::now();
-            let mut last_update = SystemTime::now();
-            let mut pause_until = SystemTime::now();
-            loop {
-                if !updater_thread.lock().unwrap().is_active() {
-                    updater_thread.lock().unwrap().write_addon_data();
-                    break;
-                }
-                updater_thread.lock().unwrap().update_addon();
-                if !updater_thread.lock().unwrap().is_update_possible() {
-                    {
-                        let status_text = format!("\nUpdate completed.");
-                        gui_data.lock().unwrap().status_text = status_text;
-                        updater_thread.lock().unwrap().update_gui();
-                    }
-                    let last_update_secs = SystemTime::now().duration_since(last_update).unwrap().as_secs();
-                    if last_update_secs > 300 {
-                        // Finished 5 minutes ago, check for due updates again
-                        updater_thread.lock().unwrap().rewrite_update_queue();
-                        last_update = SystemTime::now();
-                    }
-                    thread::sleep(Duration::new(1, 0));
-                    continue;
-                }
-                if pause_until > SystemTime::now() {
-                    thread::sleep(Duration::new(1, 0));
-                    continue;
-                }
-                let last_rate_update_secs = SystemTime::now().duration_since(last_rate_update).unwrap().as_secs();
-                if last_rate_update_secs > 15 {
-                    updater_thread.lock().unwrap().update_api_limit();
-                    last_rate_update = SystemTime::now();
-                }
-                let success = updater_thread.lock().unwrap().update_next();
-                let last_export_secs = SystemTime::now().duration_since(last_export).unwrap().as_secs();
-                if last_export_secs > 30 {
-                    updater_thread.lock().unwrap().write_addon_data();
-                    last_export = SystemTime::now();
-                }
-                last_update = SystemTime::now();
-                if success {
-                    thread::sleep(Duration::new(0, 10000));
-                } else {
-                    pause_until = SystemTime::now() + Duration::new(60, 0);
-                }
-            }
-        }));
+        self.updater_task = Some(self.updater_runtime.spawn(engine::run(self.updater_arc.clone())));
     }
 
 }
@@ -99,13 +56,14 @@ impl eframe::App for LogTrackerApp {
 
     fn on_close_event(&mut self) -> bool {
         self.updater_arc.lock().unwrap().stop();
-        if let Some(updater_thread) = self.updater_thread.take() {
-            updater_thread.join().unwrap();
+        if let Some(updater_task) = self.updater_task.take() {
+            self.updater_runtime.block_on(updater_task).unwrap();
         }
         true
     }
 
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        let mut site_selected: Option<UpdaterSite> = None;
         egui::CentralPanel::default().show(ctx, |ui| {
             let gui_data = &mut self.gui_data.lock().unwrap();
             if gui_data.ctx.is_none() {
@@ -116,6 +74,53 @@ impl eframe::App for LogTrackerApp {
                 ui.set_height( ui.available_height() - 30.0 );
                 ui.label("Configuration");
                 ui.group(|ui| {
+                    ui.label("WarcraftLogs site");
+                    egui::ComboBox::new("site", "")
+                        .width(ui.available_width())
+                        .selected_text(gui_data.site.label())
+                        .show_ui(ui, |ui| {
+                            for site in UpdaterSite::all() {
+                                if ui.selectable_label(gui_data.site == site, site.label()).clicked() {
+                                    site_selected = Some(site);
+                                }
+                            }
+                        });
+                    ui.label("Partition");
+                    let mut partition_selected: Option<UpdaterPartitionMode> = None;
+                    egui::ComboBox::new("partition", "")
+                        .width(ui.available_width())
+                        .selected_text(gui_data.partition.label(&gui_data.partitions))
+                        .show_ui(ui, |ui| {
+                            let mut partition_modes = vec![UpdaterPartitionMode::Current, UpdaterPartitionMode::All];
+                            partition_modes.extend(gui_data.partitions.iter().map(|(partition_id, _name)| UpdaterPartitionMode::Selected(*partition_id)));
+                            for partition in partition_modes {
+                                if ui.selectable_label(gui_data.partition == partition, partition.label(&gui_data.partitions)).clicked() {
+                                    partition_selected = Some(partition);
+                                }
+                            }
+                        });
+                    if let Some(partition) = partition_selected {
+                        gui_data.partition = partition;
+                        let mut updater = self.updater_arc.lock().unwrap();
+                        updater.set_partition(partition);
+                    }
+                    ui.label("Timeframe");
+                    let mut timeframe_selected: Option<UpdaterTimeframe> = None;
+                    egui::ComboBox::new("timeframe", "")
+                        .width(ui.available_width())
+                        .selected_text(gui_data.timeframe.label())
+                        .show_ui(ui, |ui| {
+                            for timeframe in UpdaterTimeframe::all() {
+                                if ui.selectable_label(gui_data.timeframe == timeframe, timeframe.label()).clicked() {
+                                    timeframe_selected = Some(timeframe);
+                                }
+                            }
+                        });
+                    if let Some(timeframe) = timeframe_selected {
+                        gui_data.timeframe = timeframe;
+                        let mut updater = self.updater_arc.lock().unwrap();
+                        updater.set_timeframe(timeframe);
+                    }
                     ui.label("Game directory");
                     ui.horizontal(|ui| {
                         ui.add( 
@@ -154,9 +159,23 @@ impl eframe::App for LogTrackerApp {
                         let mut updater = self.updater_arc.lock().unwrap();
                         updater.set_api_secret(&gui_data.api_secret);
                     }
+                    if !gui_data.auth_error.is_empty() {
+                        ui.colored_label(egui::Color32::RED, &gui_data.auth_error);
+                    }
+                    ui.horizontal(|ui| {
+                        let label_batch_size = ui.label("Characters per request");
+                        let input_batch_size = ui.add(egui::Slider::new(&mut gui_data.batch_size, 1..=20)).labelled_by(label_batch_size.id);
+                        if input_batch_size.changed() {
+                            let mut updater = self.updater_arc.lock().unwrap();
+                            updater.set_batch_size(gui_data.batch_size);
+                        }
+                    });
                 });
                 ui.label("Manual update");
                 ui.group(|ui| {
+                    if !gui_data.realm_error.is_empty() {
+                        ui.colored_label(egui::Color32::RED, &gui_data.realm_error);
+                    }
                     ui.horizontal(|ui| {
                         let input_width = (panel_width - 95.0) / 2.0;
                         ui.vertical(|ui| {
@@ -180,15 +199,70 @@ impl eframe::App for LogTrackerApp {
                             ui.set_width(60.0);
                             ui.add_space(15.0);
                             if ui.button("Update").clicked() {
-                                let mut updater = self.updater_arc.lock().unwrap();
-                                let player = updater.get_player(&gui_data.manual_realm, &gui_data.manual_player).clone();
-                                let success = updater.update_player(player);
-                                if success {
-                                    gui_data.manual_result = format!("Successfully updated {}-{}", gui_data.manual_player, gui_data.manual_realm);
-                                    updater.write_addon_data();
-                                } else {
-                                    gui_data.manual_result = format!("Failed to update {}-{}", gui_data.manual_player, gui_data.manual_realm);
-                                }
+                                let player = self.updater_arc.lock().unwrap()
+                                    .get_player(&gui_data.manual_realm, &gui_data.manual_player).clone();
+                                let player_ident = format!("{}-{}", gui_data.manual_player, gui_data.manual_realm);
+                                gui_data.manual_result = format!("Updating {}...", player_ident);
+                                let (realm, name) = (gui_data.manual_realm.clone(), gui_data.manual_player.clone());
+                                let updater_arc = self.updater_arc.clone();
+                                let gui_data_arc = self.gui_data.clone();
+                                self.updater_runtime.spawn(async move {
+                                    let manual_result = match engine::update_player(&updater_arc, player).await {
+                                        Ok(()) => {
+                                            let mut updater = updater_arc.lock().unwrap();
+                                            updater.write_addon_data();
+                                            match updater.get_player(&realm, &name).last_raided_text() {
+                                                Some(last_raided) => format!("Successfully updated {}, last raided {}", player_ident, last_raided),
+                                                None => format!("Successfully updated {}", player_ident)
+                                            }
+                                        },
+                                        Err(e) => format!("Failed to update {}: {}", player_ident, e)
+                                    };
+                                    let auth_error = updater_arc.lock().unwrap().auth_error().to_string();
+                                    let gui_data = &mut gui_data_arc.lock().unwrap();
+                                    gui_data.manual_result = manual_result;
+                                    gui_data.auth_error = auth_error;
+                                    if let Some(ctx) = &gui_data.ctx {
+                                        ctx.request_repaint();
+                                    }
+                                });
+                            }
+                        });
+                    });
+                    ui.horizontal(|ui| {
+                        let input_width = (panel_width - 95.0) / 2.0;
+                        ui.vertical(|ui| {
+                            ui.set_width(input_width);
+                            let label_manual_guild = ui.label("Guild");
+                            ui.text_edit_singleline(&mut gui_data.manual_guild).labelled_by(label_manual_guild.id);
+                        });
+                        ui.vertical(|ui| {
+                            ui.set_width(input_width);
+                            let label_manual_guild_priority = ui.label("Priority");
+                            ui.add(egui::Slider::new(&mut gui_data.manual_guild_priority, 0..=5)).labelled_by(label_manual_guild_priority.id);
+                        });
+                        ui.vertical(|ui| {
+                            ui.set_width(60.0);
+                            ui.add_space(15.0);
+                            if ui.button("Queue").clicked() {
+                                let (realm, guild_name, priority) = (gui_data.manual_realm.clone(), gui_data.manual_guild.clone(), gui_data.manual_guild_priority);
+                                let guild_ident = format!("{}-{}", guild_name, realm);
+                                gui_data.manual_result = format!("Queueing members of {}...", guild_ident);
+                                let updater_arc = self.updater_arc.clone();
+                                let gui_data_arc = self.gui_data.clone();
+                                self.updater_runtime.spawn(async move {
+                                    let manual_result = match engine::queue_guild(&updater_arc, &realm, &guild_name, priority).await {
+                                        Ok(queued_count) => format!("Queued {} members of {}", queued_count, guild_ident),
+                                        Err(e) => format!("Failed to queue {}: {}", guild_ident, e)
+                                    };
+                                    let auth_error = updater_arc.lock().unwrap().auth_error().to_string();
+                                    let gui_data = &mut gui_data_arc.lock().unwrap();
+                                    gui_data.manual_result = manual_result;
+                                    gui_data.auth_error = auth_error;
+                                    if let Some(ctx) = &gui_data.ctx {
+                                        ctx.request_repaint();
+                                    }
+                                });
                             }
                         });
                     });
@@ -203,6 +277,10 @@ impl eframe::App for LogTrackerApp {
             });
             ui.label(&gui_data.status_text);
         });
+        if let Some(site) = site_selected {
+            // Switching reloads the addon data, which needs the gui data to be unlocked
+            self.updater_arc.lock().unwrap().set_site(site);
+        }
     }
 }
 
@@ -213,8 +291,8 @@ fn main() -> Result<(), eframe::Error> {
     let icon = image::load_from_memory(ICON).unwrap().to_rgba8();
     let (icon_width, icon_height) = icon.dimensions();
     let options = eframe::NativeOptions {
-        initial_window_size: Some(egui::vec2(440.0, 300.0)),
-        min_window_size: Some(egui::vec2(440.0, 300.0)),
+        initial_window_size: Some(egui::vec2(440.0, 495.0)),
+        min_window_size: Some(egui::vec2(440.0, 495.0)),
         icon_data: Some(eframe::IconData {
             rgba: icon.into_raw(),
             width: icon_width,
@@ -226,7 +304,7 @@ fn main() -> Result<(), eframe::Error> {
         format!("LogTracker App v{}", version).as_str(), options,
         Box::new(|cc| {
             let mut app = LogTrackerApp::new(cc);
-            app.start_update_thread();
+            app.start_updater();
             Box::new(app)
         })
     )
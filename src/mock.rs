@@ -0,0 +1,419 @@
+//! Local stand-in for the WarcraftLogs token and GraphQL endpoints, so the updater can be
+//! tested end to end without network access.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use crate::slug;
+
+static MOCK_DIR_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Default)]
+pub struct MockCharacter {
+    pub id: i64,
+    pub class_id: i64,
+    /// Name of the server, the character is keyed by its slug
+    pub server: String,
+    pub level: i64,
+    pub faction: String,
+    pub hidden: bool,
+    /// Guild id, name and rank of the character, on the server it was requested from
+    pub guild: Option<(i64, String, i64)>,
+    /// Canned value returned for every aliased field with the given name, e.g. `zoneRankings`
+    pub fields: HashMap<String, Value>
+}
+
+#[derive(Clone, Default)]
+pub struct MockGuild {
+    pub name: String,
+    pub server: String,
+    /// Canned value returned for every zone and size, e.g. `{ "worldRank": { "number": 1 } }`
+    pub progress: Value,
+    /// Member names and classes, all on the server of the guild
+    pub members: Vec<(String, i64)>
+}
+
+#[derive(Default)]
+pub struct MockState {
+    pub reject_auth: bool,
+    pub token_generation: usize,
+    pub token_requests: usize,
+    /// Token requests whose connection is closed without a response
+    pub token_drops: usize,
+    pub graphql_requests: usize,
+    pub points_spent: f64,
+    pub points_limit: f64,
+    pub points_reset_in: i64,
+    pub character_cost: f64,
+    /// Characters by "name-serverSlug", unknown characters are returned as null
+    pub characters: HashMap<String, MockCharacter>,
+    /// Servers as region, name and slug
+    pub servers: Vec<(String, String, String)>,
+    /// Guilds by id, unknown guilds are returned as null
+    pub guilds: HashMap<i64, MockGuild>,
+    /// Returned instead of the character and guild data if not empty
+    pub graphql_errors: Vec<String>,
+    /// Status codes answered to the next GraphQL requests instead of a regular response
+    pub statuses: VecDeque<u16>,
+    pub zones: Value,
+    pub classes: Value
+}
+
+pub struct MockWcl {
+    address: SocketAddr,
+    state: Arc<Mutex<MockState>>
+}
+
+impl MockWcl {
+    pub async fn start() -> MockWcl {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(MockState{
+            points_limit: 3600.0,
+            points_reset_in: 3600,
+            character_cost: 2.0,
+            zones: json!({ "worldData": { "expansions": [
+                { "id": 1000, "name": "Wrath of the Lich King", "zones": [
+                    { "id": 1017, "name": "Ulduar", "frozen": false,
+                        "difficulties": [ { "id": 3, "name": "Normal", "sizes": [10, 25] }, { "id": 4, "name": "Hard Mode", "sizes": [25] } ],
+                        "encounters": [ { "id": 744, "name": "Flame Leviathan" }, { "id": 745, "name": "Ignis the Furnace Master" } ],
+                        "partitions": [ { "id": 1, "name": "Phase 1", "default": false }, { "id": 2, "name": "Phase 2", "default": true } ] }
+                ] }
+            ] } }),
+            classes: json!({ "gameData": { "classes": [
+                { "id": 1, "name": "Warrior", "slug": "Warrior", "specs": [
+                    { "id": 71, "name": "Arms", "slug": "Arms" }, { "id": 72, "name": "Fury", "slug": "Fury" }, { "id": 73, "name": "Protection", "slug": "Protection" }
+                ] },
+                { "id": 5, "name": "Priest", "slug": "Priest", "specs": [
+                    { "id": 256, "name": "Discipline", "slug": "Discipline" }, { "id": 257, "name": "Holy", "slug": "Holy" }, { "id": 258, "name": "Shadow", "slug": "Shadow" }
+                ] }
+            ] } }),
+            ..Default::default()
+        }));
+        let state_server = state.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(MockWcl::handle(stream, state_server.clone()));
+            }
+        });
+        MockWcl{ address, state }
+    }
+
+    pub fn base_url(&self) -> String {
+        format!("http://{}", self.address)
+    }
+
+    pub fn state(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap()
+    }
+
+    pub fn character(&self, name: &str, server: &str, class_id: i64, zone_rankings: Value) {
+        let mut character = MockCharacter{
+            id: 1000 + self.state().characters.len() as i64, class_id, server: server.to_string(), level: 25, faction: "Horde".to_string(),
+            ..Default::default()
+        };
+        character.fields.insert("zoneRankings".to_string(), zone_rankings);
+        self.state().characters.insert(format!("{}-{}", name, slug::server_slug(server)), character);
+    }
+
+    async fn handle(mut stream: TcpStream, state: Arc<Mutex<MockState>>) {
+        let mut request: Vec<u8> = Vec::new();
+        let mut buffer = [0_u8; 4096];
+        // Read the headers, then the body according to its length
+        let header_end = loop {
+            match stream.read(&mut buffer).await {
+                Ok(0) | Err(_) => return,
+                Ok(count) => request.extend_from_slice(&buffer[..count])
+            }
+            if let Some(header_end) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+                break header_end + 4;
+            }
+        };
+        let head = String::from_utf8_lossy(&request[..header_end]).to_string();
+        let mut head_lines = head.lines();
+        let path = head_lines.next().unwrap_or_default().split(' ').nth(1).unwrap_or_default().to_string();
+        let mut headers: HashMap<String, String> = HashMap::new();
+        for line in head_lines {
+            if let Some((name, value)) = line.split_once(':') {
+                headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+            }
+        }
+        let content_length: usize = headers.get("content-length").and_then(|length| length.parse().ok()).unwrap_or(0);
+        while request.len() < header_end + content_length {
+            match stream.read(&mut buffer).await {
+                Ok(0) | Err(_) => return,
+                Ok(count) => request.extend_from_slice(&buffer[..count])
+            }
+        }
+        let body = String::from_utf8_lossy(&request[header_end..]).to_string();
+        if path == "/oauth/token" {
+            let state = &mut state.lock().unwrap();
+            if state.token_drops > 0 {
+                state.token_drops -= 1;
+                state.token_requests += 1;
+                return;
+            }
+        }
+        let (status, response) = match path.as_str() {
+            "/oauth/token" => MockWcl::token(&mut state.lock().unwrap()),
+            "/api/v2/client" => MockWcl::graphql(&mut state.lock().unwrap(), headers.get("authorization"), &body),
+            _ => (404, json!({ "error": "Not found" }))
+        };
+        let response = response.to_string();
+        let response = format!(
+            "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status, response.len(), response
+        );
+        stream.write_all(response.as_bytes()).await.ok();
+        stream.shutdown().await.ok();
+    }
+
+    fn token(state: &mut MockState) -> (u16, Value) {
+        state.token_requests += 1;
+        if state.reject_auth {
+            return (401, json!({ "error": "invalid_client", "error_description": "Client authentication failed" }));
+        }
+        (200, json!({
+            "access_token": format!("mock-token-{}", state.token_generation),
+            "token_type": "Bearer",
+            "expires_in": 3600
+        }))
+    }
+
+    fn graphql(state: &mut MockState, authorization: Option<&String>, body: &str) -> (u16, Value) {
+        state.graphql_requests += 1;
+        if let Some(status) = state.statuses.pop_front() {
+            return (status, json!({ "error": "Mock status" }));
+        }
+        if authorization.map(|bearer| bearer.as_str()) != Some(format!("Bearer mock-token-{}", state.token_generation).as_str()) {
+            return (401, json!({ "error": "Unauthenticated." }));
+        }
+        let request: Value = serde_json::from_str(body).unwrap_or_default();
+        match request["operationName"].as_str().unwrap_or_default() {
+            "RateLimitView" => (200, json!({
+                "data": { "rateLimitData": {
+                    "limitPerHour": state.points_limit as i64,
+                    "pointsSpentThisHour": state.points_spent,
+                    "pointsResetIn": state.points_reset_in
+                } }
+            })),
+            "ZonesView" => (200, json!({ "data": state.zones })),
+            "ClassesView" => (200, json!({ "data": state.classes })),
+            "CharacterView" => MockWcl::character_view(state, request["query"].as_str().unwrap_or_default()),
+            "GuildView" => MockWcl::guild_view(state, request["query"].as_str().unwrap_or_default()),
+            "RealmView" => MockWcl::realm_view(state, request["query"].as_str().unwrap_or_default()),
+            "GuildMembersView" => MockWcl::guild_members_view(state, request["query"].as_str().unwrap_or_default()),
+            _ => (400, json!({ "error": "Unknown operation" }))
+        }
+    }
+
+    /// Answers each aliased character and field of the document from the canned characters.
+    fn character_view(state: &mut MockState, document: &str) -> (u16, Value) {
+        let mut characters: Vec<(String, Option<MockCharacter>, serde_json::Map<String, Value>)> = Vec::new();
+        for line in document.lines().map(|line| line.trim()) {
+            let Some((alias, field)) = line.split_once(": ") else {
+                continue;
+            };
+            let Some((field_name, arguments)) = field.split_once('(') else {
+                continue;
+            };
+            if field_name == "character" {
+                let argument = |name: &str| -> String {
+                    arguments.split(", ")
+                        .find_map(|argument| argument.strip_prefix(&format!("{}: ", name)))
+                        .map(|value| value.trim_end_matches([')', '{', ' ']).trim_matches('"').to_string())
+                        .unwrap_or_default()
+                };
+                let character = state.characters.get(&format!("{}-{}", argument("name"), argument("serverSlug"))).cloned();
+                let mut fields = serde_json::Map::new();
+                if let Some(character) = &character {
+                    if let Some((guild_id, guild_name, guild_rank)) = &character.guild {
+                        fields.insert("guildRank".to_string(), json!(guild_rank));
+                        fields.insert("guilds".to_string(), json!([ { "id": guild_id, "name": guild_name, "server": { "name": character.server } } ]));
+                    }
+                }
+                characters.push((alias.to_string(), character, fields));
+            } else if let Some((_alias, Some(character), fields)) = characters.last_mut() {
+                fields.insert(alias.to_string(), character.fields.get(field_name).cloned().unwrap_or(Value::Null));
+            }
+        }
+        let cost = state.character_cost * (characters.len() as f64);
+        if state.points_spent + cost > state.points_limit {
+            return (429, json!({ "error": "Too many requests" }));
+        }
+        state.points_spent += cost;
+        if !state.graphql_errors.is_empty() {
+            let errors: Vec<Value> = state.graphql_errors.iter().map(|message| json!({ "message": message })).collect();
+            return (200, json!({ "data": null, "errors": errors }));
+        }
+        let mut character_data = serde_json::Map::new();
+        for (alias, character, mut fields) in characters {
+            let character = match character {
+                Some(character) => {
+                    fields.insert("id".to_string(), json!(character.id));
+                    fields.insert("canonicalID".to_string(), json!(character.id));
+                    fields.insert("classID".to_string(), json!(character.class_id));
+                    fields.insert("level".to_string(), json!(character.level));
+                    fields.insert("faction".to_string(), json!({ "id": 2, "name": character.faction }));
+                    fields.insert("hidden".to_string(), json!(character.hidden));
+                    Value::Object(fields)
+                },
+                None => Value::Null
+            };
+            character_data.insert(alias, character);
+        }
+        (200, json!({ "data": { "characterData": character_data } }))
+    }
+
+    /// Answers server lookups by region and slug, or a page of the server lists of all regions.
+    fn realm_view(state: &mut MockState, document: &str) -> (u16, Value) {
+        let argument = |arguments: &str, name: &str| -> String {
+            arguments.split(&format!("{}: ", name)).nth(1)
+                .and_then(|value| value.split([',', ')']).next())
+                .map(|value| value.trim_matches('"').to_string())
+                .unwrap_or_default()
+        };
+        let server = |region: &str, name: &str, slug: &str| json!({ "name": name, "slug": slug, "region": { "slug": region.to_lowercase() } });
+        let mut world_data = serde_json::Map::new();
+        if document.contains("regions {") {
+            let limit: usize = argument(document, "limit").parse().unwrap_or(100);
+            let page: usize = argument(document, "page").parse().unwrap_or(1);
+            let regions: Vec<Value> = ["US", "EU", "KR", "TW", "CN"].iter().map(|region| {
+                let region_servers: Vec<&(String, String, String)> = state.servers.iter().filter(|(server_region, _name, _slug)| server_region == region).collect();
+                let servers: Vec<Value> = region_servers.iter().skip(limit * (page - 1)).take(limit)
+                    .map(|(server_region, name, slug)| server(server_region, name, slug))
+                    .collect();
+                json!({ "slug": region.to_lowercase(), "servers": { "data": servers, "has_more_pages": region_servers.len() > limit * page } })
+            }).collect();
+            world_data.insert("regions".to_string(), json!(regions));
+        }
+        for line in document.lines().map(|line| line.trim()) {
+            let Some((alias, field)) = line.split_once(": ") else {
+                continue;
+            };
+            let Some(("server", arguments)) = field.split_once('(') else {
+                continue;
+            };
+            let found = state.servers.iter().find(|(region, _name, slug)| (*region == argument(arguments, "region")) && (*slug == argument(arguments, "slug")));
+            world_data.insert(alias.to_string(), found.map_or(Value::Null, |(region, name, slug)| server(region, name, slug)));
+        }
+        (200, json!({ "data": { "worldData": world_data } }))
+    }
+
+    /// Answers a page of the members of the guild looked up by name and server.
+    fn guild_members_view(state: &mut MockState, document: &str) -> (u16, Value) {
+        let argument = |name: &str| -> String {
+            document.split(&format!("{}: ", name)).nth(1)
+                .and_then(|value| value.split([',', ')']).next())
+                .map(|value| value.trim_matches('"').to_string())
+                .unwrap_or_default()
+        };
+        let Some(guild) = state.guilds.values().find(|guild| (guild.name == argument("name")) && (slug::server_slug(&guild.server) == argument("serverSlug"))) else {
+            return (200, json!({ "data": { "guildData": { "guild": null } } }));
+        };
+        let limit: usize = argument("limit").parse().unwrap_or(100);
+        let page: usize = argument("page").parse().unwrap_or(1);
+        let members: Vec<Value> = guild.members.iter().skip(limit * (page - 1)).take(limit)
+            .map(|(name, class_id)| json!({ "name": name, "classID": class_id, "level": 80, "server": { "name": guild.server } }))
+            .collect();
+        (200, json!({ "data": { "guildData": { "guild": { "members": {
+            "data": members,
+            "has_more_pages": guild.members.len() > limit * page
+        } } } } }))
+    }
+
+    /// Answers each aliased guild, zone and progress field of the document from the canned guilds.
+    fn guild_view(state: &mut MockState, document: &str) -> (u16, Value) {
+        if !state.graphql_errors.is_empty() {
+            let errors: Vec<Value> = state.graphql_errors.iter().map(|message| json!({ "message": message })).collect();
+            return (200, json!({ "data": null, "errors": errors }));
+        }
+        let mut guild_data = serde_json::Map::new();
+        let mut guild: Option<(MockGuild, &mut Value)> = None;
+        let mut zone_alias = String::new();
+        for line in document.lines().map(|line| line.trim()) {
+            let Some((alias, field)) = line.split_once(": ") else {
+                continue;
+            };
+            let Some((field_name, arguments)) = field.split_once('(') else {
+                continue;
+            };
+            match field_name {
+                "guild" => {
+                    let guild_id: i64 = arguments.trim_start_matches("id: ").split(')').next().unwrap_or_default().parse().unwrap_or_default();
+                    let data_guild = guild_data.entry(alias.to_string()).or_insert(Value::Null);
+                    guild = match state.guilds.get(&guild_id).cloned() {
+                        Some(mock_guild) => {
+                            *data_guild = json!({ "id": guild_id, "name": mock_guild.name, "server": { "name": mock_guild.server } });
+                            Some((mock_guild, data_guild))
+                        },
+                        None => None
+                    };
+                },
+                "zoneRanking" => zone_alias = alias.to_string(),
+                "progress" => if let Some((mock_guild, data_guild)) = guild.as_mut() {
+                    data_guild[zone_alias.as_str()][alias] = mock_guild.progress.clone();
+                },
+                _ => {}
+            }
+        }
+        (200, json!({ "data": { "guildData": guild_data } }))
+    }
+}
+
+/// Creates an empty directory below the system temp dir, unique for this test run.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let mut dir = std::env::temp_dir();
+    dir.push(format!("logtrackerapp-test-{}-{}-{}", std::process::id(), MOCK_DIR_COUNT.fetch_add(1, Ordering::SeqCst), name));
+    if dir.exists() {
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Creates a game directory with the addon base data and the given players in the saved
+/// variables, as `(realm, name, class)`.
+pub fn game_dir(players: &[(&str, &str, i64)]) -> PathBuf {
+    let game_dir = temp_dir("game");
+    let mut saved_dir = game_dir.clone();
+    saved_dir.push("WTF/Account/MOCK/SavedVariables");
+    fs::create_dir_all(&saved_dir).unwrap();
+    let mut realms: HashMap<&str, Vec<String>> = HashMap::new();
+    for (realm, name, class) in players {
+        realms.entry(realm).or_default().push(format!(
+            "[\"{}\"] = {{ lastUpdate = 1, lastUpdateLogs = 0, class = {}, level = 0, faction = \"Alliance\", priority = 0 }}", name, class
+        ));
+    }
+    let realms: Vec<String> = realms.iter().map(|(realm, players)| format!("[\"{}\"] = {{ {} }}", realm, players.join(", "))).collect();
+    saved_dir.push("LogTracker.lua");
+    fs::write(saved_dir, format!("LogTrackerDB = {{ playerData = {{ {} }} }}", realms.join(", "))).unwrap();
+    let mut addon_dir = game_dir.clone();
+    addon_dir.push("Interface/AddOns/LogTracker");
+    fs::create_dir_all(&addon_dir).unwrap();
+    let mut base_data_dir = game_dir.clone();
+    base_data_dir.push("Interface/AddOns/LogTracker_BaseData");
+    fs::create_dir_all(&base_data_dir).unwrap();
+    base_data_dir.push("LogTracker_BaseData.lua");
+    let mut regions: Vec<String> = players.iter().map(|(realm, _name, _class)| format!("[\"{}\"] = \"EU\"", realm)).collect();
+    regions.dedup();
+    fs::write(base_data_dir, format!(r#"LogTracker_BaseData = {{
+    classes = {{
+        ["1"] = {{ id = 1, name = "Warrior", slug = "Warrior", specs = {{
+            ["1"] = {{ id = 1, name = "Arms", slug = "Arms", metric = "dps" }},
+            ["2"] = {{ id = 2, name = "Fury", slug = "Fury", metric = "dps" }}
+        }} }},
+        ["5"] = {{ id = 5, name = "Priest", slug = "Priest", specs = {{
+            ["1"] = {{ id = 51, name = "Holy", slug = "Holy", metric = "hps" }}
+        }} }}
+    }},
+    regionByServerName = {{ {} }}
+}}"#, regions.join(", "))).unwrap();
+    game_dir
+}
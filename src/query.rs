@@ -0,0 +1,331 @@
+use serde_json::json;
+
+/// Builds the CharacterView document at runtime, so any number of zones and
+/// characters can be requested in a single query by aliasing the fields.
+#[derive(Clone, Default)]
+pub struct CharacterQuery {
+    name: String,
+    server_slug: String,
+    server_region: String,
+    zones: Vec<(i64, Vec<i64>, Vec<i64>)>,
+    specs: Vec<(i64, String, String)>,
+    timeframes: Vec<String>,
+    brackets: bool,
+    recent_reports: bool,
+    encounters: Vec<(i64, i64, i64)>
+}
+
+impl CharacterQuery {
+    pub fn new(name: &str, server_slug: &str, server_region: &str) -> CharacterQuery {
+        CharacterQuery{
+            name: name.to_string(), server_slug: server_slug.to_string(), server_region: server_region.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Requests the rankings of a zone, partition 0 being the default partition of the zone
+    pub fn zone(mut self, zone_id: i64, sizes: Vec<i64>, partitions: Vec<i64>) -> CharacterQuery {
+        self.zones.push((zone_id, sizes, partitions));
+        self
+    }
+
+    pub fn spec(mut self, spec_index: i64, spec_name: &str, metric: &str) -> CharacterQuery {
+        self.specs.push((spec_index, spec_name.to_string(), metric.to_string()));
+        self
+    }
+
+    /// Requests the zone rankings in the given timeframe, without any the API default is used
+    pub fn timeframe(mut self, timeframe: &str) -> CharacterQuery {
+        self.timeframes.push(timeframe.to_string());
+        self
+    }
+
+    /// Requests the item level bracket rankings in addition to the overall ones
+    pub fn brackets(mut self) -> CharacterQuery {
+        self.brackets = true;
+        self
+    }
+
+    /// Requests the start time of the most recent report of the character
+    pub fn recent_reports(mut self) -> CharacterQuery {
+        self.recent_reports = true;
+        self
+    }
+
+    pub fn encounter(mut self, encounter_id: i64, difficulty: i64, size: i64) -> CharacterQuery {
+        self.encounters.push((encounter_id, difficulty, size));
+        self
+    }
+
+    pub fn character_alias(character_index: usize) -> String {
+        format!("c{}", character_index)
+    }
+
+    pub fn alias(zone_id: i64, zone_size: i64, partition: i64, timeframe: &str, spec_index: i64) -> String {
+        CharacterQuery::ranking_alias(zone_id, zone_size, partition, timeframe, spec_index, false)
+    }
+
+    pub fn bracket_alias(zone_id: i64, zone_size: i64, partition: i64, timeframe: &str, spec_index: i64) -> String {
+        CharacterQuery::ranking_alias(zone_id, zone_size, partition, timeframe, spec_index, true)
+    }
+
+    fn ranking_alias(zone_id: i64, zone_size: i64, partition: i64, timeframe: &str, spec_index: i64, bracket: bool) -> String {
+        let partition = match partition {
+            0 => String::new(),
+            _ => format!("Partition{}", partition)
+        };
+        let timeframe = match timeframe {
+            "Today" => "Today",
+            _ => ""
+        };
+        let bracket = match bracket {
+            true => "Bracket",
+            false => ""
+        };
+        format!("zoneRankings{}Size{}{}{}{}Spec{}", zone_id, zone_size, partition, timeframe, bracket, spec_index)
+    }
+
+    pub fn encounter_alias(encounter_id: i64, difficulty: i64, size: i64, spec_index: i64) -> String {
+        format!("encounterRankings{}Difficulty{}Size{}Spec{}", encounter_id, difficulty, size, spec_index)
+    }
+
+    pub fn spec_count(&self) -> usize {
+        self.specs.len()
+    }
+
+    /// Describes the layout of the requested rankings, queries with the same shape
+    /// and spec count are expected to cost the same amount of points.
+    pub fn shape(&self) -> String {
+        let zones: Vec<String> = self.zones.iter()
+            .map(|(zone_id, sizes, partitions)| match partitions.as_slice() {
+                [0] => format!("{}x{}", zone_id, sizes.len()),
+                _ => format!("{}x{}p{}", zone_id, sizes.len(), partitions.len())
+            })
+            .collect();
+        let mut zones = match self.timeframes.len() {
+            0 | 1 => zones.join("+"),
+            timeframes => format!("{}t{}", zones.join("+"), timeframes)
+        };
+        if self.brackets {
+            zones.push_str("+b");
+        }
+        if self.recent_reports {
+            zones.push_str("+r");
+        }
+        match self.encounters.len() {
+            0 => zones,
+            encounters => format!("{}+e{}", zones, encounters)
+        }
+    }
+
+    fn fields(&self, character_index: usize) -> String {
+        let mut fields: Vec<String> = Vec::new();
+        let default_timeframes = vec![String::new()];
+        let timeframes = match self.timeframes.is_empty() {
+            true => &default_timeframes,
+            false => &self.timeframes
+        };
+        for (zone_id, sizes, partitions) in self.zones.iter() {
+            for zone_size in sizes.iter() {
+                for partition in partitions.iter() {
+                    for timeframe in timeframes.iter() {
+                        let mut arguments = String::new();
+                        if *partition != 0 {
+                            arguments.push_str(&format!(", partition: {}", partition));
+                        }
+                        if !timeframe.is_empty() {
+                            arguments.push_str(&format!(", timeframe: {}", timeframe));
+                        }
+                        for (spec_index, spec_name, metric) in self.specs.iter() {
+                            fields.push(format!(
+                                "            {}: zoneRankings(zoneID: {}, specName: {}, size: {}, metric: {}{})",
+                                CharacterQuery::alias(*zone_id, *zone_size, *partition, timeframe, *spec_index),
+                                zone_id, json!(spec_name), zone_size, metric, arguments
+                            ));
+                            if self.brackets {
+                                fields.push(format!(
+                                    "            {}: zoneRankings(zoneID: {}, specName: {}, size: {}, metric: {}{}, byBracket: true)",
+                                    CharacterQuery::bracket_alias(*zone_id, *zone_size, *partition, timeframe, *spec_index),
+                                    zone_id, json!(spec_name), zone_size, metric, arguments
+                                ));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        if self.recent_reports {
+            fields.push("            recentReports: recentReports(limit: 1) { data { startTime } }".to_string());
+        }
+        for (encounter_id, difficulty, size) in self.encounters.iter() {
+            for (spec_index, spec_name, metric) in self.specs.iter() {
+                fields.push(format!(
+                    "            {}: encounterRankings(encounterID: {}, difficulty: {}, size: {}, specName: {}, metric: {})",
+                    CharacterQuery::encounter_alias(*encounter_id, *difficulty, *size, *spec_index),
+                    encounter_id, difficulty, size, json!(spec_name), metric
+                ));
+            }
+        }
+        format!(
+            "        {}: character(name: {}, serverSlug: {}, serverRegion: {}) {{\n            \
+                id\n            \
+                canonicalID\n            \
+                classID\n            \
+                level\n            \
+                name\n            \
+                hidden\n            \
+                faction {{ id name }}\n            \
+                guildRank\n            \
+                guilds {{ id name server {{ name }} }}\n\
+                {}\n        \
+            }}",
+            CharacterQuery::character_alias(character_index),
+            json!(self.name), json!(self.server_slug), json!(self.server_region),
+            fields.join("\n")
+        )
+    }
+
+    pub fn document(queries: &[CharacterQuery]) -> String {
+        let characters: Vec<String> = queries.iter().enumerate()
+            .map(|(character_index, query)| query.fields(character_index))
+            .collect();
+        format!(
+            "query CharacterView {{\n    \
+                characterData {{\n\
+                    {}\n    \
+                }}\n\
+            }}",
+            characters.join("\n")
+        )
+    }
+}
+
+/// Builds the GuildView document requesting the progress ranks of guilds by id.
+#[derive(Clone, Default)]
+pub struct GuildQuery {
+    guild_id: i64,
+    zones: Vec<(i64, Vec<i64>)>
+}
+
+impl GuildQuery {
+    pub fn new(guild_id: i64) -> GuildQuery {
+        GuildQuery{ guild_id, ..Default::default() }
+    }
+
+    pub fn zone(mut self, zone_id: i64, sizes: Vec<i64>) -> GuildQuery {
+        self.zones.push((zone_id, sizes));
+        self
+    }
+
+    pub fn guild_alias(guild_index: usize) -> String {
+        format!("g{}", guild_index)
+    }
+
+    pub fn zone_alias(zone_id: i64) -> String {
+        format!("zoneRanking{}", zone_id)
+    }
+
+    pub fn progress_alias(zone_size: i64) -> String {
+        format!("progress{}", zone_size)
+    }
+
+    fn fields(&self, guild_index: usize) -> String {
+        let mut fields: Vec<String> = Vec::new();
+        for (zone_id, sizes) in self.zones.iter() {
+            let progress: Vec<String> = sizes.iter()
+                .map(|zone_size| format!(
+                    "                {}: progress(size: {}) {{ worldRank {{ number }} regionRank {{ number }} serverRank {{ number }} }}",
+                    GuildQuery::progress_alias(*zone_size), zone_size
+                ))
+                .collect();
+            fields.push(format!(
+                "            {}: zoneRanking(zoneId: {}) {{\n{}\n            }}",
+                GuildQuery::zone_alias(*zone_id), zone_id, progress.join("\n")
+            ));
+        }
+        format!(
+            "        {}: guild(id: {}) {{\n            \
+                id\n            \
+                name\n            \
+                server {{ name }}\n\
+                {}\n        \
+            }}",
+            GuildQuery::guild_alias(guild_index), self.guild_id, fields.join("\n")
+        )
+    }
+
+    /// Document for one page of the members of a guild, looked up by name
+    pub fn members_document(guild_name: &str, server_slug: &str, server_region: &str, limit: i64, page: i64) -> String {
+        format!(
+            "query GuildMembersView {{\n    \
+                guildData {{\n        \
+                    guild(name: {}, serverSlug: {}, serverRegion: {}) {{\n            \
+                        members(limit: {}, page: {}) {{\n                \
+                            data {{ name classID level server {{ name }} }}\n                \
+                            has_more_pages\n            \
+                        }}\n        \
+                    }}\n    \
+                }}\n\
+            }}",
+            json!(guild_name), json!(server_slug), json!(server_region), limit, page
+        )
+    }
+
+    pub fn document(queries: &[GuildQuery]) -> String {
+        let guilds: Vec<String> = queries.iter().enumerate()
+            .map(|(guild_index, query)| query.fields(guild_index))
+            .collect();
+        format!(
+            "query GuildView {{\n    \
+                guildData {{\n\
+                    {}\n    \
+                }}\n\
+            }}",
+            guilds.join("\n")
+        )
+    }
+}
+
+/// Builds the RealmView documents used to look up the region and slug of a realm.
+pub struct RealmQuery;
+
+impl RealmQuery {
+    pub const REGIONS: [&'static str; 5] = ["US", "EU", "KR", "TW", "CN"];
+
+    pub fn region_alias(region: &str) -> String {
+        region.to_lowercase()
+    }
+
+    /// Looks up the slug in every region at once
+    pub fn server_document(server_slug: &str) -> String {
+        let servers: Vec<String> = RealmQuery::REGIONS.iter()
+            .map(|region| format!(
+                "        {}: server(region: {}, slug: {}) {{ name slug }}",
+                RealmQuery::region_alias(region), json!(region), json!(server_slug)
+            ))
+            .collect();
+        format!(
+            "query RealmView {{\n    \
+                worldData {{\n\
+                    {}\n    \
+                }}\n\
+            }}",
+            servers.join("\n")
+        )
+    }
+
+    /// Document for one page of the server lists of all regions
+    pub fn servers_document(limit: i64, page: i64) -> String {
+        format!(
+            "query RealmView {{\n    \
+                worldData {{\n        \
+                    regions {{\n            \
+                        slug\n            \
+                        servers(limit: {}, page: {}) {{ data {{ name slug }} has_more_pages }}\n        \
+                    }}\n    \
+                }}\n\
+            }}",
+            limit, page
+        )
+    }
+}
@@ -0,0 +1,94 @@
+//! Server slugs as used by WarcraftLogs, derived from the realm names shown in game.
+
+/// Realms whose slug can't be derived from the name, mostly Russian realms that use the
+/// English name as slug.
+const SLUG_OVERRIDES: [(&str, &str); 9] = [
+    ("Пламегор", "flamegor"),
+    ("Хроми", "chromie"),
+    ("Рок-Делар", "rhokdelar"),
+    ("Змейталак", "wyrmthalak"),
+    ("Вестник Рока", "harbinger-of-doom"),
+    ("Галакронд", "galakrond"),
+    ("Гордунни", "gordunni"),
+    ("Ревущий фьорд", "howling-fjord"),
+    ("Свежеватель Душ", "soulflayer")
+];
+
+/// Slug of a realm name: lower case without accents, apostrophes, dashes and parentheses,
+/// words joined by dashes. "Aggra (Português)" becomes "aggra-portugues".
+pub fn server_slug(realm: &str) -> String {
+    if let Some((_realm, slug)) = SLUG_OVERRIDES.iter().find(|(override_realm, _slug)| override_realm.to_lowercase() == realm.to_lowercase()) {
+        return slug.to_string();
+    }
+    let mut slug = String::new();
+    for realm_char in realm.trim().to_lowercase().chars() {
+        match realm_char {
+            '\'' | '’' | '-' | '(' | ')' | '.' => {},
+            realm_char if realm_char.is_whitespace() => {
+                if !slug.is_empty() && !slug.ends_with('-') {
+                    slug.push('-');
+                }
+            },
+            realm_char => slug.push_str(&strip_accent(realm_char))
+        }
+    }
+    slug.trim_end_matches('-').to_string()
+}
+
+fn strip_accent(realm_char: char) -> String {
+    let stripped = match realm_char {
+        'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' => 'a',
+        'ç' => 'c',
+        'è' | 'é' | 'ê' | 'ë' => 'e',
+        'ì' | 'í' | 'î' | 'ï' => 'i',
+        'ñ' => 'n',
+        'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' => 'o',
+        'ù' | 'ú' | 'û' | 'ü' => 'u',
+        'ý' | 'ÿ' => 'y',
+        'ß' => return "ss".to_string(),
+        'æ' => return "ae".to_string(),
+        realm_char => realm_char
+    };
+    stripped.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::server_slug;
+
+    #[test]
+    fn eu_realms() {
+        assert_eq!(server_slug("Pyrewood Village"), "pyrewood-village");
+        assert_eq!(server_slug("Mirage Raceway"), "mirage-raceway");
+        assert_eq!(server_slug("Hydraxian Waterlords"), "hydraxian-waterlords");
+        assert_eq!(server_slug("Nethergarde Keep"), "nethergarde-keep");
+        assert_eq!(server_slug("Zirkel des Cenarius"), "zirkel-des-cenarius");
+        assert_eq!(server_slug("Chants éternels"), "chants-eternels");
+        assert_eq!(server_slug("Confrérie du Thorium"), "confrerie-du-thorium");
+        assert_eq!(server_slug("Pozzo dell'Eternità"), "pozzo-delleternita");
+        assert_eq!(server_slug("Aggra (Português)"), "aggra-portugues");
+        assert_eq!(server_slug("Azjol-Nerub"), "azjolnerub");
+    }
+
+    #[test]
+    fn us_realms() {
+        assert_eq!(server_slug("Faerlina"), "faerlina");
+        assert_eq!(server_slug("Old Blanchy"), "old-blanchy");
+        assert_eq!(server_slug("Bloodsail Buccaneers"), "bloodsail-buccaneers");
+        assert_eq!(server_slug("Kil'jaeden"), "kiljaeden");
+        assert_eq!(server_slug("Mok'Nathal"), "moknathal");
+        assert_eq!(server_slug("Area 52"), "area-52");
+        assert_eq!(server_slug("  Crusader  Strike "), "crusader-strike");
+    }
+
+    #[test]
+    fn ru_realms() {
+        assert_eq!(server_slug("Пламегор"), "flamegor");
+        assert_eq!(server_slug("Хроми"), "chromie");
+        assert_eq!(server_slug("Рок-Делар"), "rhokdelar");
+        assert_eq!(server_slug("Вестник Рока"), "harbinger-of-doom");
+        assert_eq!(server_slug("вестник рока"), "harbinger-of-doom");
+        // Unknown Russian realms keep their name, lower case
+        assert_eq!(server_slug("Новый Мир"), "новый-мир");
+    }
+}
@@ -6,8 +6,9 @@ use std::fs::{self, File};
 use std::io::Write;
 use std::sync::{Mutex, Arc};
 use std::time::{Duration, SystemTime};
-use std::thread::sleep;
+use std::cmp::Reverse;
 use std::collections::HashMap;
+use std::fmt;
 use chrono::offset::Local;
 use chrono::DateTime;
 use eframe::egui;
@@ -15,33 +16,251 @@ use log::{info, warn};
 use mlua::prelude::*;
 use mlua::Table;
 use serde::{Serialize, Deserialize};
-use reqwest::{self, blocking::Client};
-use oauth2::{AuthUrl,ClientId,ClientSecret,TokenResponse,TokenUrl, StandardTokenResponse, EmptyExtraTokenFields};
-use oauth2::basic::{BasicClient, BasicTokenType};
-use oauth2::reqwest::http_client;
-use graphql_client::{reqwest::post_graphql_blocking as post_graphql, GraphQLQuery};
+use graphql_client::{GraphQLQuery, Response};
+use crate::api::{WclApi, RETRY_MAX, RETRY_BASE_MS};
+use crate::query::{CharacterQuery, GuildQuery, RealmQuery};
+use crate::slug;
 
 const UPDATE_INTERVAL_TURBO: i64 = 86400;       // 1 day
 const UPDATE_INTERVAL_FAST: i64 = 86400 * 2;    // 2 days
 const UPDATE_INTERVAL_SLOW: i64 = 604800;       // 1 week
+const UPDATE_INTERVAL_ZONES: i64 = 86400;       // 1 day
+const UPDATE_INTERVAL_CLASSES: i64 = 604800;    // 1 week
+const UPDATE_INTERVAL_HIDDEN: i64 = 604800 * 4; // 4 weeks
+const UPDATE_INTERVAL_GUILDS: i64 = 86400 * 3;  // 3 days
+const INACTIVE_AFTER: i64 = 604800 * 4;         // No report for 4 weeks, only update slowly
+const UPDATE_INTERVAL_REALMS: i64 = 86400;      // Retry failed realm lookups after 1 day
+const REALM_SERVERS_PAGE: i64 = 100;            // Servers per region requested per page
+const REALM_SERVERS_PAGES_MAX: i64 = 20;        // Give up on a realm after 2000 servers per region
+const GUILD_MEMBERS_PAGE: i64 = 100;            // Guild members requested per page
+const GUILD_MEMBERS_PAGES_MAX: i64 = 10;        // Stop paging through huge rosters after 1000 members
+const ZONES_VERSION: i64 = 2;                   // Bumped when more zone details are cached
+const COST_SAMPLES_MIN: i64 = 5;                // Samples needed before a cost estimate is trusted
+const COST_SAMPLE_INTERVAL: i64 = 3600;         // Resample known query costs once per hour
+const MAX_IN_FLIGHT: usize = 4;                 // Character requests sent at the same time
+const POINTS_RESERVE: f64 = 600.0;              // Points kept back until shortly before the rate limit resets
+const BATCH_SIZE: usize = 10;                   // Characters per request unless configured otherwise
+
+/// Values of the API `CharacterRankingMetricType`
+const RANKING_METRICS: [&str; 25] = [
+    "bossdps", "bossndps", "bossrdps", "default", "dps", "hps", "krsi", "playerscore", "playerspeed",
+    "ndps", "rdps", "tankhps", "wdps",
+    "healercombineddps", "healercombinedbossdps", "healercombinedndps", "healercombinedbossndps",
+    "healercombinedrdps", "healercombinedbossrdps",
+    "tankcombineddps", "tankcombinedbossdps", "tankcombinedndps", "tankcombinedbossndps",
+    "tankcombinedrdps", "tankcombinedbossrdps"
+];
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Hash, Debug)]
+pub enum UpdaterSite {
+    #[default]
+    Classic,
+    Fresh,
+    Era,
+    SeasonOfDiscovery,
+    Retail
+}
 
 #[derive(Serialize, Deserialize, Default)]
 pub struct UpdaterConfig {
     game_dir: Box<str>,
     api_id: Box<str>,
-    api_secret: Box<str>
+    api_secret: Box<str>,
+    #[serde(default)]
+    site: UpdaterSite,
+    #[serde(default)]
+    site_game_dirs: HashMap<UpdaterSite, Box<str>>,
+    #[serde(default)]
+    zone_ids: Vec<i64>,
+    #[serde(default)]
+    zone_count: usize,
+    #[serde(default = "default_batch_size")]
+    batch_size: usize,
+    #[serde(default)]
+    retry_max: Option<u32>,
+    #[serde(default)]
+    retry_base_ms: Option<u64>,
+    #[serde(default)]
+    max_in_flight: usize,
+    #[serde(default)]
+    api_base_url: Option<Box<str>>,
+    #[serde(default)]
+    encounter_rankings: bool,
+    #[serde(default)]
+    partition: UpdaterPartitionMode,
+    #[serde(default)]
+    timeframe: UpdaterTimeframe,
+    /// Ranking metric by spec id of the base data, takes precedence over the base data metric
+    #[serde(default)]
+    metric_overrides: HashMap<i64, Box<str>>,
+    #[serde(default)]
+    bracket_rankings: bool,
+    #[serde(default)]
+    guild_progress: bool,
+    #[serde(default)]
+    recent_reports: bool
+}
+
+fn default_batch_size() -> usize {
+    BATCH_SIZE
+}
+
+/// Current time in seconds since the unix epoch
+fn unix_now() -> i64 {
+    i64::try_from(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()).unwrap()
+}
+
+/// Quoted Lua string with quotes and backslashes escaped
+fn lua_string(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Which partitions (gear phases) of a zone are ranked
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum UpdaterPartitionMode {
+    #[default]
+    Current,
+    All,
+    Selected(i64)
+}
+
+impl UpdaterPartitionMode {
+    /// Label for the settings, selected partitions are named after the given (id, name) list
+    pub fn label(&self, partitions: &[(i64, String)]) -> String {
+        match self {
+            UpdaterPartitionMode::Current => "Current partition".to_string(),
+            UpdaterPartitionMode::All => "All partitions".to_string(),
+            UpdaterPartitionMode::Selected(partition_id) => {
+                match partitions.iter().find(|(id, _name)| id == partition_id) {
+                    Some((_id, name)) => name.clone(),
+                    None => format!("Partition {}", partition_id)
+                }
+            }
+        }
+    }
+}
+
+/// Ranking timeframe, compared against all parses or only against the ones of today
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum UpdaterTimeframe {
+    #[default]
+    Historical,
+    Today,
+    Both
+}
+
+impl UpdaterTimeframe {
+    pub fn all() -> [UpdaterTimeframe; 3] {
+        [UpdaterTimeframe::Historical, UpdaterTimeframe::Today, UpdaterTimeframe::Both]
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            UpdaterTimeframe::Historical => "Historical",
+            UpdaterTimeframe::Today => "Today",
+            UpdaterTimeframe::Both => "Historical and today"
+        }
+    }
+
+    /// Values of the API `RankingTimeframeType` to request
+    pub fn query_timeframes(&self) -> Vec<&'static str> {
+        match self {
+            UpdaterTimeframe::Historical => vec!["Historical"],
+            UpdaterTimeframe::Today => vec!["Today"],
+            UpdaterTimeframe::Both => vec!["Historical", "Today"]
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct UpdaterCache {
+    zones: Vec<UpdaterZone>,
+    zones_updated: i64,
+    #[serde(default)]
+    zones_version: i64,
+    #[serde(default)]
+    query_costs: HashMap<String, UpdaterQueryCost>,
+    #[serde(default)]
+    guilds: HashMap<i64, UpdaterGuildProgress>,
+    /// Previous "name-realm" of renamed or transferred characters
+    #[serde(default)]
+    renames: HashMap<String, UpdaterRename>,
+    /// Realms missing from the base data, looked up through the API
+    #[serde(default)]
+    realms: HashMap<String, UpdaterRealm>,
+    /// Classes and specs from the API, used if the base data addon is missing
+    #[serde(default)]
+    classes: HashMap<String, UpdaterBaseDataClass>,
+    #[serde(default)]
+    classes_updated: i64
+}
+
+/// Entry a renamed or transferred character was merged into
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Debug)]
+pub struct UpdaterRename {
+    /// Current "name-realm" of the character
+    ident: String,
+    /// Time of the merge, saved entries seen later belong to another character taking the name
+    merged: i64
+}
+
+/// Region and slug of a realm, both empty if the lookup failed
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Debug)]
+pub struct UpdaterRealm {
+    region: Box<str>,
+    slug: Box<str>,
+    updated: i64
+}
+
+/// Progress ranks of a guild by "zone-size" as world, region and server rank, 0 if unranked
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Debug)]
+pub struct UpdaterGuildProgress {
+    name: Box<str>,
+    realm: Box<str>,
+    updated: i64,
+    ranks: HashMap<String, (i64, i64, i64)>
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct UpdaterQueryCost {
+    points: f64,
+    samples: i64,
+    last_sample: i64
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum UpdateError {
+    UnknownRealm(String),
+    CharacterNotFound(String),
+    GuildNotFound(String),
+    RateLimited(Option<SystemTime>),
+    Auth(String),
+    Network(String),
+    Rejected(String),
+    UnknownClass(i64),
+    Parse(String)
 }
 
 #[derive(Clone, Default)]
 pub struct UpdaterGuiData {
     pub ctx: Option<egui::Context>,
+    pub site: UpdaterSite,
     pub game_dir: String,
     pub api_id: String,
     pub api_secret: String,
     pub manual_realm: String,
     pub manual_player: String,
     pub manual_result: String,
+    pub manual_guild: String,
+    pub manual_guild_priority: i64,
+    pub batch_size: usize,
+    pub partition: UpdaterPartitionMode,
+    /// Partitions of the selected zones as id and name
+    pub partitions: Vec<(i64, String)>,
+    pub timeframe: UpdaterTimeframe,
     pub status_text: String,
+    pub auth_error: String,
+    pub realm_error: String,
     pub realm_list: Vec<String>
 }
 
@@ -59,15 +278,91 @@ pub struct UpdaterPlayer {
     last_update: i64,
     last_update_logs: i64,
     last_update_addon: i64,
-    update_priority: i64
+    update_priority: i64,
+    hidden: bool,
+    profile_synced: bool,
+    boss_rankings: Vec<UpdaterBossRanking>,
+    guild: Option<UpdaterPlayerGuild>,
+    last_raided: i64,
+    canonical_id: i64
+}
+
+impl UpdaterPlayer {
+    pub fn realm(&self) -> &str {
+        &self.realm
+    }
+
+    /// Carries over the data of the same character under a previous name or realm
+    fn merge_history(&mut self, previous: UpdaterPlayer) {
+        self.priority = self.priority.max(previous.priority);
+        self.last_raided = self.last_raided.max(previous.last_raided);
+        for (zone_ident, ranking) in previous.ranking {
+            self.ranking.entry(zone_ident).or_insert(ranking);
+        }
+        if self.encounter.is_empty() {
+            self.encounter = previous.encounter;
+            self.encounter_kills = previous.encounter_kills;
+        }
+        if self.boss_rankings.is_empty() {
+            self.boss_rankings = previous.boss_rankings;
+        }
+        if self.guild.is_none() {
+            self.guild = previous.guild;
+        }
+    }
+
+    /// Date of the newest report the character appeared in, if known
+    pub fn last_raided_text(&self) -> Option<String> {
+        if self.last_raided == 0 {
+            return None;
+        }
+        let last_raided = SystemTime::UNIX_EPOCH + Duration::new(u64::try_from(self.last_raided).unwrap_or_default(), 0);
+        let last_raided_dt: DateTime<Local> = last_raided.into();
+        Some(last_raided_dt.format("%F").to_string())
+    }
+}
+
+/// Primary guild of a character
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct UpdaterPlayerGuild {
+    id: i64,
+    name: Box<str>,
+    realm: Box<str>,
+    rank: i64
+}
+
+impl UpdaterPlayerGuild {
+    pub fn from_json(data_char: &serde_json::Value) -> Option<UpdaterPlayerGuild> {
+        let data_guild = data_char["guilds"].as_array()?.iter().find(|data_guild| !data_guild.is_null())?;
+        Some(UpdaterPlayerGuild{
+            id: data_guild["id"].as_i64()?,
+            name: data_guild["name"].as_str()?.into(),
+            realm: data_guild["server"]["name"].as_str().unwrap_or_default().into(),
+            rank: data_char["guildRank"].as_i64().unwrap_or_default()
+        })
+    }
+
+    pub fn from_lua(data: Table) -> Option<UpdaterPlayerGuild> {
+        Some(UpdaterPlayerGuild{
+            id: data.get(1).ok()?,
+            name: data.get::<_, String>(2).ok()?.into(),
+            realm: data.get::<_, String>(3).unwrap_or_default().into(),
+            rank: data.get(4).unwrap_or_default()
+        })
+    }
+
+    pub fn to_export(&self) -> String {
+        format!("{{{},{},{},{}}}", self.id, lua_string(&self.name), lua_string(&self.realm), self.rank)
+    }
 }
 
 #[derive(Clone, Default)]
 pub struct UpdaterRanking {
     encounters: i64,
     encounters_killed: i64,
-    allstar_ratings: Vec<(i64,i64,i64)>,
-    encounter_ratings: Vec<(i64,i64,i64)>
+    allstar_ratings: Vec<(i64,i64,i64,Box<str>)>,
+    encounter_ratings: Vec<(i64,i64,i64)>,
+    bracket_ratings: Vec<(i64,i64,i64)>
 }
 
 #[derive(Clone, Default)]
@@ -77,6 +372,220 @@ pub struct UpdaterEncounter {
     hardmode_label: String,
 }
 
+/// Best and median parse of a single boss on one difficulty and raid size, for one spec
+/// ranked by the same metric as the zone rankings of the spec.
+#[derive(Clone, Default, Debug, PartialEq)]
+pub struct UpdaterBossRanking {
+    encounter_id: i64,
+    difficulty: i64,
+    size: i64,
+    spec_id: i64,
+    best: i64,
+    median: i64,
+    kills: i64,
+    metric: Box<str>
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct UpdaterZone {
+    id: i64,
+    name: Box<str>,
+    expansion_id: i64,
+    frozen: bool,
+    sizes: Vec<i64>,
+    #[serde(default)]
+    difficulties: Vec<(i64, Vec<i64>)>,
+    #[serde(default)]
+    encounters: Vec<i64>,
+    #[serde(default)]
+    partitions: Vec<UpdaterPartition>
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct UpdaterPartition {
+    id: i64,
+    name: Box<str>,
+    default: bool
+}
+
+impl fmt::Display for UpdateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UpdateError::UnknownRealm(realm) => write!(f, "Unknown realm {}", realm),
+            UpdateError::CharacterNotFound(character) => write!(f, "Character {} not found", character),
+            UpdateError::GuildNotFound(guild) => write!(f, "Guild {} not found", guild),
+            UpdateError::RateLimited(Some(resume_at)) => {
+                let resume_dt: DateTime<Local> = (*resume_at).into();
+                write!(f, "Rate limit reached, resuming at {}", resume_dt.format("%R"))
+            },
+            UpdateError::RateLimited(None) => write!(f, "Rate limit reached"),
+            UpdateError::Auth(message) => write!(f, "Authentication failed: {}", message),
+            UpdateError::Network(message) => write!(f, "Network error: {}", message),
+            UpdateError::Rejected(message) => write!(f, "Request rejected: {}", message),
+            UpdateError::UnknownClass(class_id) => write!(f, "Unknown class {}", class_id),
+            UpdateError::Parse(message) => write!(f, "Invalid response: {}", message)
+        }
+    }
+}
+
+impl std::error::Error for UpdateError {}
+
+impl UpdaterSite {
+    pub fn all() -> [UpdaterSite; 5] {
+        [UpdaterSite::Classic, UpdaterSite::Fresh, UpdaterSite::Era, UpdaterSite::SeasonOfDiscovery, UpdaterSite::Retail]
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            UpdaterSite::Classic => "Classic",
+            UpdaterSite::Fresh => "Classic Fresh",
+            UpdaterSite::Era => "Classic Era",
+            UpdaterSite::SeasonOfDiscovery => "Season of Discovery",
+            UpdaterSite::Retail => "Retail"
+        }
+    }
+
+    pub fn ident(&self) -> &'static str {
+        match self {
+            UpdaterSite::Classic => "classic",
+            UpdaterSite::Fresh => "fresh",
+            UpdaterSite::Era => "era",
+            UpdaterSite::SeasonOfDiscovery => "sod",
+            UpdaterSite::Retail => "retail"
+        }
+    }
+
+    pub fn host(&self) -> &'static str {
+        match self {
+            UpdaterSite::Classic => "classic.warcraftlogs.com",
+            UpdaterSite::Fresh => "fresh.warcraftlogs.com",
+            UpdaterSite::Era => "vanilla.warcraftlogs.com",
+            UpdaterSite::SeasonOfDiscovery => "sod.warcraftlogs.com",
+            UpdaterSite::Retail => "www.warcraftlogs.com"
+        }
+    }
+
+    pub fn base_url(&self) -> String {
+        format!("https://{}", self.host())
+    }
+
+    /// Level cap of the game version, only max level characters are ranked
+    pub fn max_level(&self) -> i64 {
+        match self {
+            UpdaterSite::Classic => 80,
+            UpdaterSite::Fresh => 60,
+            UpdaterSite::Era => 60,
+            UpdaterSite::SeasonOfDiscovery => 60,
+            UpdaterSite::Retail => 80
+        }
+    }
+}
+
+impl UpdaterZone {
+    /// Every size the zone is ranked in, by the zone list and by each of its difficulties
+    pub fn query_sizes(&self) -> Vec<i64> {
+        let mut sizes = self.sizes.clone();
+        for (_difficulty, difficulty_sizes) in self.difficulties.iter() {
+            sizes.extend(difficulty_sizes);
+        }
+        sizes.sort();
+        sizes.dedup();
+        sizes
+    }
+
+    /// Partitions to request for the given mode, 0 being the default partition
+    pub fn query_partitions(&self, mode: UpdaterPartitionMode) -> Vec<i64> {
+        match mode {
+            UpdaterPartitionMode::All if !self.partitions.is_empty() => {
+                self.partitions.iter().map(|partition| partition.id).collect()
+            },
+            UpdaterPartitionMode::Selected(partition_id) if self.partitions.iter().any(|partition| partition.id == partition_id) => {
+                vec![partition_id]
+            },
+            _ => vec![0]
+        }
+    }
+
+    /// Key of the rankings in the export, the default partition and historical rankings keep
+    /// the plain zone and size key
+    pub fn ranking_key(&self, zone_size: i64, partition: i64, timeframe: &str) -> String {
+        let default_partition = partition == 0 || self.partitions.iter().any(|zone_partition| zone_partition.id == partition && zone_partition.default);
+        let mut ranking_key = match default_partition {
+            true => format!("{}-{}", self.id, zone_size),
+            false => format!("{}-{}-p{}", self.id, zone_size, partition)
+        };
+        if timeframe == "Today" {
+            ranking_key.push_str("-today");
+        }
+        ranking_key
+    }
+
+    /// Size, partition and timeframe of every zone ranking requested for the given settings
+    pub fn query_rankings(&self, partition_mode: UpdaterPartitionMode, timeframe: UpdaterTimeframe) -> Vec<(i64, i64, &'static str)> {
+        let mut rankings: Vec<(i64, i64, &'static str)> = Vec::new();
+        for zone_size in self.query_sizes() {
+            for partition in self.query_partitions(partition_mode) {
+                for query_timeframe in timeframe.query_timeframes() {
+                    rankings.push((zone_size, partition, query_timeframe));
+                }
+            }
+        }
+        rankings
+    }
+
+    /// Difficulty and size combinations every boss of the zone is ranked in
+    pub fn query_difficulties(&self) -> Vec<(i64, i64)> {
+        let mut difficulties: Vec<(i64, i64)> = Vec::new();
+        for (difficulty, sizes) in self.difficulties.iter() {
+            for zone_size in self.query_sizes() {
+                if sizes.is_empty() || sizes.contains(&zone_size) {
+                    difficulties.push((*difficulty, zone_size));
+                }
+            }
+        }
+        difficulties
+    }
+}
+
+impl UpdaterBossRanking {
+    pub fn from_json(encounter_id: i64, difficulty: i64, size: i64, spec_id: i64, metric: &str, data: &serde_json::Value) -> Option<UpdaterBossRanking> {
+        let kills = data.get("totalKills").and_then(|kills| kills.as_i64()).unwrap_or(0);
+        if kills == 0 {
+            return None;
+        }
+        let best = data.get("ranks").and_then(|ranks| ranks.as_array()).into_iter().flatten()
+            .filter_map(|rank| rank.get("rankPercent").and_then(|percent| percent.as_f64()))
+            .fold(0.0, f64::max);
+        let median = data.get("medianPerformance").and_then(|median| median.as_f64()).unwrap_or(0.0);
+        Some(UpdaterBossRanking{
+            encounter_id, difficulty, size, spec_id,
+            best: best.round() as i64, median: median.round() as i64, kills, metric: metric.into()
+        })
+    }
+
+    /// Parses the `encounter-difficulty-size-spec,best,median,kills,metric` format used in the export
+    pub fn from_export(data: &str) -> Option<UpdaterBossRanking> {
+        let (boss_ident, ratings) = data.split_once(',')?;
+        let (ratings, metric) = ratings.rsplit_once(',')?;
+        let boss_ident: Vec<i64> = boss_ident.split('-').filter_map(|value| value.parse().ok()).collect();
+        let ratings: Vec<i64> = ratings.split(',').filter_map(|value| value.parse().ok()).collect();
+        match (boss_ident.as_slice(), ratings.as_slice()) {
+            ([encounter_id, difficulty, size, spec_id], [best, median, kills]) if !metric.is_empty() => Some(UpdaterBossRanking{
+                encounter_id: *encounter_id, difficulty: *difficulty, size: *size, spec_id: *spec_id,
+                best: *best, median: *median, kills: *kills, metric: metric.into()
+            }),
+            _ => None
+        }
+    }
+
+    pub fn to_export(&self) -> String {
+        format!(
+            "{}-{}-{}-{},{},{},{},{}",
+            self.encounter_id, self.difficulty, self.size, self.spec_id, self.best, self.median, self.kills, self.metric
+        )
+    }
+}
+
 #[derive(Clone, Default)]
 pub struct UpdaterBaseData {
     classes: HashMap<String, UpdaterBaseDataClass>,
@@ -84,7 +593,7 @@ pub struct UpdaterBaseData {
 }
 
 
-#[derive(Clone, Default)]
+#[derive(Serialize, Deserialize, Clone, Default)]
 pub struct UpdaterBaseDataClass {
     id: i64,
     name: Box<str>,
@@ -92,7 +601,18 @@ pub struct UpdaterBaseDataClass {
     specs: HashMap<String, UpdaterBaseDataClassSpec>
 }
 
-#[derive(Clone, Default)]
+impl UpdaterBaseDataClass {
+    /// Specs by their numeric index in the base data, in order, however many the class has
+    pub fn query_specs(&self) -> Vec<(i64, &UpdaterBaseDataClassSpec)> {
+        let mut specs: Vec<(i64, &UpdaterBaseDataClassSpec)> = self.specs.iter()
+            .filter_map(|(spec_ident, spec_details)| spec_ident.parse::<i64>().ok().map(|spec_index| (spec_index, spec_details)))
+            .collect();
+        specs.sort_by_key(|(spec_index, _spec_details)| *spec_index);
+        specs
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
 pub struct UpdaterBaseDataClassSpec {
     id: i64,
     name: Box<str>,
@@ -106,15 +626,24 @@ impl UpdaterRanking {
         self.encounters_killed = 0;
         self.allstar_ratings.clear();
         self.encounter_ratings.clear();
+        self.bracket_ratings.clear();
+    }
+    /// Adds the all stars of a rankings request by item level bracket
+    pub fn update_bracket_from_json(&mut self, data: &serde_json::Value, spec_id: i64) {
+        if let Some(best) = data["bestPerformanceAverage"].as_f64() {
+            let median = data["medianPerformanceAverage"].as_f64().unwrap_or_default();
+            self.bracket_ratings.push((spec_id, best.round() as i64, median.round() as i64));
+        }
     }
-    pub fn update_from_json(&mut self, data: &serde_json::Value, spec_id: i64) {
+    pub fn update_from_json(&mut self, data: &serde_json::Value, spec_id: i64, metric: &str) {
         // Fill with new data
         if let Some(best) = data.get("bestPerformanceAverage") {
             if !best.is_null() {
                 self.allstar_ratings.push((
                     spec_id, 
                     best.as_f64().unwrap().round() as i64,
-                    data.get("medianPerformanceAverage").unwrap().as_f64().unwrap().round() as i64
+                    data.get("medianPerformanceAverage").unwrap().as_f64().unwrap().round() as i64,
+                    metric.into()
                 ));
             }
         }
@@ -124,10 +653,9 @@ impl UpdaterRanking {
                 // Clear encounter stats
                 self.encounters = 0;
                 self.encounters_killed = 0;
-                let mut encounter_index = 0;
-                for encounter_rank in encounters.iter() {
+                for (encounter_index, encounter_rank) in encounters.iter().enumerate() {
                     if self.encounter_ratings.len() <= encounter_index {
-                        self.encounter_ratings.push((0 as i64, 0 as i64, 0 as i64));
+                        self.encounter_ratings.push((0_i64, 0_i64, 0_i64));
                     }
                     let encounter_rating = self.encounter_ratings.get_mut(encounter_index).unwrap();
                     self.encounters += 1;
@@ -144,7 +672,6 @@ impl UpdaterRanking {
                     if encounter_rating.1 > 0 {
                         self.encounters_killed += 1;
                     }
-                    encounter_index += 1;
                 }
             }
         }
@@ -153,13 +680,18 @@ impl UpdaterRanking {
         // Clear values
         self.allstar_ratings.clear();
         self.encounter_ratings.clear();
+        self.bracket_ratings.clear();
         // Update from lua
         self.encounters = data.get(1).unwrap();
         self.encounters_killed = data.get(2).unwrap();
         let data_allstars: Table = data.get(3).unwrap();
         for pair_allstar in data_allstars.pairs::<String, Table>() {
             let (_allstar_index, allstar_details) = pair_allstar.unwrap();
-            self.allstar_ratings.push((allstar_details.get(1).unwrap(), allstar_details.get(2).unwrap(), allstar_details.get(3).unwrap_or_default()));
+            let metric: Option<String> = allstar_details.get(4).unwrap_or_default();
+            self.allstar_ratings.push((
+                allstar_details.get(1).unwrap(), allstar_details.get(2).unwrap(), allstar_details.get(3).unwrap_or_default(),
+                metric.unwrap_or_default().into()
+            ));
         }
         let data_encounters_str: String = data.get(4).unwrap();
         let data_encounters: Vec<&str> = data_encounters_str.split("|").collect();
@@ -168,44 +700,116 @@ impl UpdaterRanking {
             if !data_encounter.is_empty() {
                 let data_ratings: Vec<&str> = data_encounter.split(",").collect();
                 self.encounter_ratings.push((
-                    data_ratings.get(0).unwrap().parse::<i64>().or_else(|_| Ok::<i64, i64>(0)).unwrap(),
-                    data_ratings.get(1).unwrap().parse::<i64>().or_else(|_| Ok::<i64, i64>(0)).unwrap(),
-                    data_ratings.get(2).unwrap().parse::<i64>().or_else(|_| Ok::<i64, i64>(0)).unwrap()
+                    data_ratings.first().unwrap().parse::<i64>().unwrap_or(0),
+                    data_ratings.get(1).unwrap().parse::<i64>().unwrap_or(0),
+                    data_ratings.get(2).unwrap().parse::<i64>().unwrap_or(0)
                 ));
             }
         }
+        if let Ok(Some(data_brackets)) = data.get::<_, Option<Table>>(5) {
+            for pair_bracket in data_brackets.pairs::<i64, Table>() {
+                let (_bracket_index, bracket_details) = pair_bracket.unwrap();
+                self.bracket_ratings.push((bracket_details.get(1).unwrap(), bracket_details.get(2).unwrap(), bracket_details.get(3).unwrap_or_default()));
+            }
+        }
     }
 }
 
-type JSON = serde_json::Value;
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "graphql/schema.graphql",
+    query_path = "graphql/rate_limit.graphql",
+    response_derives = "Debug",
+)]
+pub struct RateLimitView;
 
 #[derive(GraphQLQuery)]
 #[graphql(
     schema_path = "graphql/schema.graphql",
-    query_path = "graphql/character.graphql",
+    query_path = "graphql/zones.graphql",
     response_derives = "Debug",
 )]
-pub struct CharacterView;
+pub struct ZonesView;
 
 #[derive(GraphQLQuery)]
 #[graphql(
     schema_path = "graphql/schema.graphql",
-    query_path = "graphql/rate_limit.graphql",
+    query_path = "graphql/classes.graphql",
     response_derives = "Debug",
 )]
-pub struct RateLimitView;
+pub struct ClassesView;
+
+/// Characters prepared for a single CharacterView request, which is sent without
+/// holding the updater lock and applied afterwards.
+#[derive(Default)]
+pub struct UpdaterRequest {
+    api: Option<WclApi>,
+    api_generation: u64,
+    document: String,
+    zones: Vec<UpdaterZone>,
+    batch: Vec<(usize, UpdaterPlayer)>,
+    results: Vec<Result<(), UpdateError>>,
+    cost_key: String,
+    cost_estimate: f64,
+    cost_sample: bool
+}
+
+impl UpdaterRequest {
+    pub fn is_empty(&self) -> bool {
+        self.batch.is_empty()
+    }
+
+    pub fn is_cost_sample(&self) -> bool {
+        self.cost_sample
+    }
+
+    pub async fn send(&self) -> Result<Response<serde_json::Value>, UpdateError> {
+        match &self.api {
+            Some(api) => api.query_document("CharacterView", &self.document).await,
+            None => Err(UpdateError::Auth("Missing API credentials".to_string()))
+        }
+    }
+}
+
+/// Progress request for the guilds of known players, prepared like `UpdaterRequest`.
+#[derive(Default)]
+pub struct UpdaterGuildRequest {
+    api: Option<WclApi>,
+    api_generation: u64,
+    document: String,
+    zones: Vec<UpdaterZone>,
+    guilds: Vec<i64>
+}
+
+impl UpdaterGuildRequest {
+    pub async fn send(&self) -> Result<Response<serde_json::Value>, UpdateError> {
+        match &self.api {
+            Some(api) => api.query_document("GuildView", &self.document).await,
+            None => Err(UpdateError::Auth("Missing API credentials".to_string()))
+        }
+    }
+}
 
 pub struct Updater {
     active: bool,
     config: UpdaterConfig,
+    cache: UpdaterCache,
+    data_dir: PathBuf,
     gui_data_arc: Option<Arc<Mutex<UpdaterGuiData>>>,
     base_data: UpdaterBaseData,
     players: HashMap<String, HashMap<String, UpdaterPlayer>>,
     update_addon: SystemTime,
     update_queue: Vec<UpdaterPlayer>,
     update_queue_pos: usize,
+    /// Estimated points and number of players needed to get from the queue start to each position
+    update_queue_costs: Vec<(f64, usize)>,
     update_priority_only: bool,
-    wcl_token: String,
+    api: WclApi,
+    api_generation: u64,
+    requests_in_flight: usize,
+    points_in_flight: f64,
+    cost_sampling: bool,
+    auth_error: String,
     wcl_points_used: f64,
     wcl_points_limit: f64,
     wcl_reset_at: SystemTime
@@ -215,15 +819,23 @@ impl Updater {
     pub fn new() -> Updater {
         Updater{
             active: true,
-            config: Default::default(),
+            config: UpdaterConfig{ batch_size: BATCH_SIZE, ..Default::default() },
+            cache: Default::default(),
+            data_dir: home::home_dir().unwrap_or_default(),
             gui_data_arc: None,
             base_data: Default::default(),
             players: HashMap::new(),
             update_addon: SystemTime::UNIX_EPOCH,
             update_queue: Vec::new(),
             update_queue_pos: 0,
+            update_queue_costs: Vec::new(),
             update_priority_only: false,
-            wcl_token: Default::default(),
+            api: WclApi::new(&UpdaterSite::default().base_url(), "", "", RETRY_MAX, RETRY_BASE_MS),
+            api_generation: 0,
+            requests_in_flight: 0,
+            points_in_flight: 0.0,
+            cost_sampling: false,
+            auth_error: String::new(),
             wcl_points_used: Default::default(),
             wcl_points_limit: Default::default(),
             wcl_reset_at: SystemTime::now()
@@ -242,11 +854,11 @@ impl Updater {
         (self.update_queue_pos < self.update_queue.len()) && !self.config.api_id.is_empty() && !self.config.api_secret.is_empty()
     }
 
-    pub fn get_player(&mut self, realm: &String, player_name: &String) -> &mut UpdaterPlayer {
-        let realm_players = self.players.entry(realm.clone()).or_insert_with(|| HashMap::new());
-        realm_players.entry(player_name.clone()).or_insert_with(|| {
+    pub fn get_player(&mut self, realm: &str, player_name: &str) -> &mut UpdaterPlayer {
+        let realm_players = self.players.entry(realm.to_string()).or_default();
+        realm_players.entry(player_name.to_string()).or_insert_with(|| {
             UpdaterPlayer{
-                realm: realm.as_str().into(), name: player_name.as_str().into(),
+                realm: realm.into(), name: player_name.into(),
                 faction: "Unknown".into(), class: 0, level: 0, priority: 0,
                 last_update: 0, last_update_logs: 0, last_update_addon: 0,
                 update_priority: 0,
@@ -255,6 +867,25 @@ impl Updater {
         })
     }
 
+    /// Replaces the api handle after the site or credentials changed, requests still in
+    /// flight are discarded once they return.
+    fn reset_api(&mut self) {
+        // The base url can be overridden to go through a proxy or a local test server
+        let base_url = match &self.config.api_base_url {
+            Some(base_url) => base_url.to_string(),
+            None => self.config.site.base_url()
+        };
+        self.api = WclApi::new(
+            &base_url, &self.config.api_id, &self.config.api_secret,
+            self.config.retry_max.unwrap_or(RETRY_MAX), self.config.retry_base_ms.unwrap_or(RETRY_BASE_MS)
+        );
+        self.api_generation += 1;
+    }
+
+    pub fn api(&self) -> WclApi {
+        self.api.clone()
+    }
+
     fn modify_gui_data(&self, force: bool, callback: impl FnOnce(&mut UpdaterGuiData)) {
         if let Some(gui_data_arc) = &self.gui_data_arc {
             if force {
@@ -273,17 +904,67 @@ impl Updater {
 
     pub fn set_game_dir(&mut self, game_dir: &str) {
         self.config.game_dir = game_dir.into();
+        self.config.site_game_dirs.insert(self.config.site, game_dir.into());
         self.write_config();
         self.read_addon_data();
     }
 
+    pub fn set_site(&mut self, site: UpdaterSite) {
+        if site == self.config.site {
+            return;
+        }
+        // Every site keeps its own game directory and player data
+        if !self.config.game_dir.is_empty() {
+            self.write_addon_data();
+            self.config.site_game_dirs.insert(self.config.site, self.config.game_dir.clone());
+        }
+        self.config.site = site;
+        self.config.game_dir = self.config.site_game_dirs.get(&site).cloned().unwrap_or_default();
+        self.write_config();
+        self.players.clear();
+        self.base_data = Default::default();
+        self.update_addon = SystemTime::UNIX_EPOCH;
+        self.update_queue.clear();
+        self.update_queue_pos = 0;
+        self.update_queue_costs.clear();
+        self.reset_api();
+        self.cache = Default::default();
+        self.load_cache();
+        self.modify_gui_data(true, |gui_data| {
+            gui_data.site = site;
+            gui_data.game_dir = self.config.game_dir.to_string();
+            gui_data.realm_list.clear();
+            gui_data.manual_realm.clear();
+        });
+        self.read_addon_data();
+    }
+
     pub fn set_api_id(&mut self, api_id: &str) {
         self.config.api_id = api_id.into();
+        self.reset_api();
         self.write_config();
     }
 
     pub fn set_api_secret(&mut self, api_secret: &str) {
         self.config.api_secret = api_secret.into();
+        self.reset_api();
+        self.write_config();
+    }
+
+    pub fn set_partition(&mut self, partition: UpdaterPartitionMode) {
+        self.config.partition = partition;
+        self.write_config();
+        self.refresh_queue_costs();
+    }
+
+    pub fn set_timeframe(&mut self, timeframe: UpdaterTimeframe) {
+        self.config.timeframe = timeframe;
+        self.write_config();
+        self.refresh_queue_costs();
+    }
+
+    pub fn set_batch_size(&mut self, batch_size: usize) {
+        self.config.batch_size = batch_size.max(1);
         self.write_config();
     }
 
@@ -291,88 +972,92 @@ impl Updater {
         let mut realm_list: Vec<String> = Vec::new();
         let game_dir_str = String::from(self.config.game_dir.clone());
         let game_dir = PathBuf::from(game_dir_str);
-        let mut game_wtf_accounts = PathBuf::from(game_dir.clone());
+        let mut game_wtf_accounts = game_dir.clone();
         game_wtf_accounts.push("WTF");
         game_wtf_accounts.push("Account");
         if !game_wtf_accounts.is_dir() {
             return
         }
-        for account_dir in game_wtf_accounts.read_dir().expect("Failed to read WoW-Accounts!") {
-            if let Ok(account_dir) = account_dir {
-                let mut addon_lua_saved = PathBuf::from(account_dir.path());
-                addon_lua_saved.push("SavedVariables");
-                addon_lua_saved.push("LogTracker.lua");
-                if addon_lua_saved.is_file() {
-                    let addon_lua_meta = fs::metadata(addon_lua_saved.clone()).unwrap();
-                    let addon_lua_mod = addon_lua_meta.modified().unwrap();
-                    if addon_lua_mod > self.update_addon {
-                        self.update_addon = addon_lua_mod;
-                    }
-                    let lua = Lua::new();
-                    let data_raw = fs::read_to_string(addon_lua_saved).unwrap();
-                    if !lua.load(data_raw.as_str()).exec().is_err() {
-                        let data: Table = lua.globals().get("LogTrackerDB").unwrap();
-                        let data_realms: Table = data.get("playerData").unwrap();
-                        for pair_realm in data_realms.pairs::<String, Table>() {
-                            let (realm_name, player_list) = pair_realm.unwrap();
-                            if !realm_list.contains(&realm_name) {
-                                realm_list.push(realm_name.clone());
+        for account_dir in game_wtf_accounts.read_dir().expect("Failed to read WoW-Accounts!").flatten() {
+            let mut addon_lua_saved = account_dir.path();
+            addon_lua_saved.push("SavedVariables");
+            addon_lua_saved.push("LogTracker.lua");
+            if addon_lua_saved.is_file() {
+                let addon_lua_meta = fs::metadata(addon_lua_saved.clone()).unwrap();
+                let addon_lua_mod = addon_lua_meta.modified().unwrap();
+                if addon_lua_mod > self.update_addon {
+                    self.update_addon = addon_lua_mod;
+                }
+                let lua = Lua::new();
+                let data_raw = fs::read_to_string(addon_lua_saved).unwrap();
+                if lua.load(data_raw.as_str()).exec().is_ok() {
+                    let data: Table = lua.globals().get("LogTrackerDB").unwrap();
+                    let data_realms: Table = data.get("playerData").unwrap();
+                    for pair_realm in data_realms.pairs::<String, Table>() {
+                        let (realm_name, player_list) = pair_realm.unwrap();
+                        if !realm_list.contains(&realm_name) {
+                            realm_list.push(realm_name.clone());
+                        }
+                        for pair_player in player_list.pairs::<String, Table>() {
+                            let (player_name, player_details) = pair_player.unwrap();
+                            let player_updated: i64 = player_details.get("lastUpdate").unwrap();
+                            let player_updated_logs: i64 = player_details.get("lastUpdateLogs").unwrap_or(0);
+                            let player_priority: i64 = player_details.get("priority").unwrap_or(0);
+                            let player_class: i64 = player_details.get("class").unwrap_or(0);
+                            let player_level: i64 = player_details.get("level").unwrap_or(0);
+                            if self.is_merged_away(&realm_name, &player_name, player_updated) {
+                                continue; // Merged into the entry of the new name or realm
                             }
-                            for pair_player in player_list.pairs::<String, Table>() {
-                                let (player_name, player_details) = pair_player.unwrap();
-                                let player_updated: i64 = player_details.get("lastUpdate").unwrap();
-                                let player_updated_logs: i64 = player_details.get("lastUpdateLogs").or_else(|_| Ok::<i64, i64>(0)).unwrap();
-                                let player_priority: i64 = player_details.get("priority").or_else(|_| Ok::<i64, i64>(0)).unwrap();
-                                let player_class: i64 = player_details.get("class").or_else(|_| Ok::<i64, i64>(0)).unwrap();
-                                let player_level: i64 = player_details.get("level").or_else(|_| Ok::<i64, i64>(0)).unwrap();
-                                let mut player = &mut self.get_player(&realm_name, &player_name);
+                            let player = &mut self.get_player(&realm_name, &player_name);
+                            if !player.profile_synced {
+                                // Level and faction from the API take precedence
                                 player.faction = player_details.get("faction").or_else(|_| Ok::<String, String>("Unknown".to_string())).unwrap().as_str().into();
-                                player.class = player_class;
                                 player.level = player_level;
-                                player.priority = player_priority;
-                                player.last_update = player_updated;
-                                player.last_update_logs = player_updated_logs;
-                                player.last_update_addon = player_updated;
-                                if let Ok(player_encounters) = player_details.get::<String, Table>("encounters".to_string()) {
-                                    let mut encounter_kills: i64 = 0;
-                                    for pair_encounter in player_encounters.pairs::<String, String>() {
-                                        let (zone_id, encounter_details_str) = pair_encounter.unwrap();
-                                        let mut zone_kills: i64 = 0;
-                                        let mut encounter_details: Vec<UpdaterEncounter> = Vec::new();
-                                        for encounter_data in encounter_details_str.split("/") {
-                                            let mut encounter_entry = UpdaterEncounter{ ..Default::default() };
-                                            if !encounter_data.is_empty() {
-                                                let mut encounter_data_fields = encounter_data.split(",");
-                                                if let Some(kill_count) = encounter_data_fields.next() {
-                                                    encounter_entry.kill_count = str::parse(kill_count).unwrap();
-                                                    if encounter_entry.kill_count > 0 {
-                                                        zone_kills += 1;
-                                                    }
-                                                }
-                                                if let Some(hardmode_difficulty) = encounter_data_fields.next() {
-                                                    encounter_entry.hardmode_difficulty = str::parse(hardmode_difficulty).unwrap();
-                                                }
-                                                if let Some(hardmode_label) = encounter_data_fields.next() {
-                                                    encounter_entry.hardmode_label = hardmode_label.to_string();
+                            }
+                            player.class = player_class;
+                            player.priority = player_priority;
+                            player.last_update = player_updated;
+                            player.last_update_logs = player_updated_logs;
+                            player.last_update_addon = player_updated;
+                            if let Ok(player_encounters) = player_details.get::<String, Table>("encounters".to_string()) {
+                                let mut encounter_kills: i64 = 0;
+                                for pair_encounter in player_encounters.pairs::<String, String>() {
+                                    let (zone_id, encounter_details_str) = pair_encounter.unwrap();
+                                    let mut zone_kills: i64 = 0;
+                                    let mut encounter_details: Vec<UpdaterEncounter> = Vec::new();
+                                    for encounter_data in encounter_details_str.split("/") {
+                                        let mut encounter_entry = UpdaterEncounter{ ..Default::default() };
+                                        if !encounter_data.is_empty() {
+                                            let mut encounter_data_fields = encounter_data.split(",");
+                                            if let Some(kill_count) = encounter_data_fields.next() {
+                                                encounter_entry.kill_count = str::parse(kill_count).unwrap();
+                                                if encounter_entry.kill_count > 0 {
+                                                    zone_kills += 1;
                                                 }
                                             }
-                                            encounter_details.push(encounter_entry);
+                                            if let Some(hardmode_difficulty) = encounter_data_fields.next() {
+                                                encounter_entry.hardmode_difficulty = str::parse(hardmode_difficulty).unwrap();
+                                            }
+                                            if let Some(hardmode_label) = encounter_data_fields.next() {
+                                                encounter_entry.hardmode_label = hardmode_label.to_string();
+                                            }
                                         }
-                                        encounter_kills = encounter_kills.max(zone_kills);
-                                        player.encounter.insert(zone_id.to_string(), encounter_details);
+                                        encounter_details.push(encounter_entry);
                                     }
-                                    player.encounter_kills = encounter_kills;
+                                    encounter_kills = encounter_kills.max(zone_kills);
+                                    player.encounter.insert(zone_id.to_string(), encounter_details);
                                 }
+                                player.encounter_kills = encounter_kills;
                             }
                         }
-                        if let Ok(update_priority_only) = data.get("appPriorityOnly") {
-                            self.update_priority_only = update_priority_only;
-                        }
+                    }
+                    if let Ok(update_priority_only) = data.get("appPriorityOnly") {
+                        self.update_priority_only = update_priority_only;
                     }
                 }
             }
         }
-        let mut addon_lua_base_data = PathBuf::from(game_dir.clone());
+        let mut addon_lua_base_data = game_dir.clone();
         addon_lua_base_data.push("Interface");
         addon_lua_base_data.push("AddOns");
         addon_lua_base_data.push("LogTracker_BaseData");
@@ -380,7 +1065,7 @@ impl Updater {
         if addon_lua_base_data.is_file() {
             let lua = Lua::new();
             let data_raw = fs::read_to_string(addon_lua_base_data).unwrap();
-            if !lua.load(data_raw.as_str()).exec().is_err() {
+            if lua.load(data_raw.as_str()).exec().is_ok() {
                 let data: Table = lua.globals().get("LogTracker_BaseData").unwrap();
                 let data_classes: Table = data.get("classes").unwrap();
                 for pair_class in data_classes.pairs::<String, Table>() {
@@ -406,7 +1091,7 @@ impl Updater {
                 }
             }
         }
-        let mut addon_lua_import = PathBuf::from(game_dir);
+        let mut addon_lua_import = game_dir;
         addon_lua_import.push("Interface");
         addon_lua_import.push("AddOns");
         addon_lua_import.push("LogTracker");
@@ -414,7 +1099,7 @@ impl Updater {
         if addon_lua_import.is_file() {
             let lua = Lua::new();
             let data_raw = fs::read_to_string(addon_lua_import).unwrap();
-            if !lua.load(data_raw.as_str()).exec().is_err() {
+            if lua.load(data_raw.as_str()).exec().is_ok() {
                 let data: Table = lua.globals().get("LogTracker_AppData").unwrap();
                 for pair_realm in data.pairs::<String, Table>() {
                     let (realm_name, player_list) = pair_realm.unwrap();
@@ -424,11 +1109,21 @@ impl Updater {
                     for pair_player in player_list.pairs::<String, Table>() {
                         let (player_name, player_details) = pair_player.unwrap();
                         let import_last_update: i64 = player_details.get(4).unwrap();
+                        if self.is_merged_away(&realm_name, &player_name, import_last_update) {
+                            continue;
+                        }
                         let player = &mut self.get_player(&realm_name, &player_name);
                         if import_last_update > player.last_update {
                             player.level = player_details.get(1).unwrap();
                             player.faction = player_details.get(2).unwrap();
                             player.class = player_details.get(3).unwrap();
+                            player.hidden = player_details.get("hidden").unwrap_or(false);
+                            let player_bosses: String = player_details.get("bosses").unwrap_or_default();
+                            player.boss_rankings = player_bosses.split('|').filter_map(UpdaterBossRanking::from_export).collect();
+                            player.guild = player_details.get::<_, Option<Table>>("guild").ok().flatten().and_then(UpdaterPlayerGuild::from_lua);
+                            player.last_raided = player_details.get("raided").unwrap_or_default();
+                            player.canonical_id = player_details.get("id").unwrap_or_default();
+                            player.profile_synced = true;
                             player.last_update = import_last_update;
                             player.last_update_logs = import_last_update;
                             let player_encounters: Table = player_details.get(5).unwrap();
@@ -444,7 +1139,7 @@ impl Updater {
             }
         }
         self.modify_gui_data(true, |gui_data| {
-            if realm_list.len() > 0 {
+            if !realm_list.is_empty() {
                 gui_data.realm_list = realm_list;
                 let gui_manual_realm = &mut gui_data.manual_realm;
                 if gui_manual_realm.is_empty() {
@@ -456,13 +1151,16 @@ impl Updater {
     }
 
     pub fn write_addon_data(&mut self) {
+        if self.config.game_dir.is_empty() {
+            return; // No game directory set for the site yet
+        }
         // Serialize data for export
         let mut result = "LogTracker_AppData = {\n".to_string();
         let mut realms: Vec<String> = Vec::new();
         for (realm, player_list) in self.players.iter() {
-            let mut realm_str = String::from("  [\"");
-            realm_str.push_str(realm);
-            realm_str.push_str("\"] = {\n");
+            let mut realm_str = String::from("  [");
+            realm_str.push_str(&lua_string(realm));
+            realm_str.push_str("] = {\n");
             let mut players: Vec<String> = Vec::new();
             for (name, player) in player_list.iter() {
                 if player.last_update_addon < player.last_update {
@@ -474,8 +1172,8 @@ impl Updater {
                     let mut zones: Vec<String> = Vec::new();
                     for (zone_ident, ranking) in player.ranking.iter() {
                         let mut allstars: Vec<String> = Vec::new();
-                        for (spec_id, best, median) in ranking.allstar_ratings.iter() {
-                            let allstar_str = format!("{{{},{},{}}}", spec_id, best, median);
+                        for (spec_id, best, median, metric) in ranking.allstar_ratings.iter() {
+                            let allstar_str = format!("{{{},{},{},\"{}\"}}", spec_id, best, median, metric);
                             allstars.push(allstar_str);
                         }
                         let mut encounters: Vec<String> = Vec::new();
@@ -483,17 +1181,40 @@ impl Updater {
                             let encounter_str = format!("{},{},{}", spec_id, best, median);
                             encounters.push(encounter_str);
                         }
-                        let zone_str = format!("[\"{}\"] = {{{},{},{{{}}},\"{}\"}}",
+                        let mut zone_str = format!("[\"{}\"] = {{{},{},{{{}}},\"{}\"",
                             zone_ident, ranking.encounters, ranking.encounters_killed, allstars.join(","), encounters.join("|")
                         );
+                        if !ranking.bracket_ratings.is_empty() {
+                            let brackets: Vec<String> = ranking.bracket_ratings.iter()
+                                .map(|(spec_id, best, median)| format!("{{{},{},{}}}", spec_id, best, median))
+                                .collect();
+                            zone_str.push_str(&format!(",{{{}}}", brackets.join(",")));
+                        }
+                        zone_str.push('}');
                         zones.push(zone_str);
                     }
                     data_player.push(format!("{{ {} }}", zones.join(",")));
-                    let mut player_str = String::from("    [\"");
-                    player_str.push_str(name);
-                    player_str.push_str("\"] = {");
+                    if player.hidden {
+                        data_player.push("hidden=true".to_string());
+                    }
+                    if !player.boss_rankings.is_empty() {
+                        let bosses: Vec<String> = player.boss_rankings.iter().map(|boss_ranking| boss_ranking.to_export()).collect();
+                        data_player.push(format!("bosses=\"{}\"", bosses.join("|")));
+                    }
+                    if let Some(guild) = &player.guild {
+                        data_player.push(format!("guild={}", guild.to_export()));
+                    }
+                    if player.last_raided > 0 {
+                        data_player.push(format!("raided={}", player.last_raided));
+                    }
+                    if player.canonical_id > 0 {
+                        data_player.push(format!("id={}", player.canonical_id));
+                    }
+                    let mut player_str = String::from("    [");
+                    player_str.push_str(&lua_string(name));
+                    player_str.push_str("] = {");
                     player_str.push_str(&data_player.join(","));
-                    player_str.push_str("}");
+                    player_str.push('}');
                     players.push(player_str);
                 }
             }
@@ -503,10 +1224,11 @@ impl Updater {
         }
         result.push_str(&realms.join(",\n"));
         result.push_str("\n}");
+        result.push_str(&self.guild_export());
         // Write to disk
         let game_dir_str = String::from(self.config.game_dir.clone());
         let game_dir = PathBuf::from(game_dir_str);
-        let mut addon_lua_import = PathBuf::from(game_dir);
+        let mut addon_lua_import = game_dir;
         addon_lua_import.push("Interface");
         addon_lua_import.push("AddOns");
         addon_lua_import.push("LogTracker");
@@ -540,15 +1262,16 @@ impl Updater {
     }
 
     pub fn rewrite_update_queue(&mut self) {
-        let now = i64::try_from(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()).unwrap();
+        let now = unix_now();
         self.update_queue_pos = 0;
         self.update_queue.clear();
+        let max_level = self.config.site.max_level();
         for pair_realm in self.players.iter() {
             let (_realm_name, player_list) = pair_realm;
             for pair_player in player_list.iter() {
                 let (_player_name, player_details) = pair_player;
-                if (player_details.level > 0) && (player_details.level < 80) {
-                    continue; // Skip non-80 players
+                if (player_details.level > 0) && (player_details.level < max_level) {
+                    continue; // Skip players below the level cap
                 }
                 if player_details.class == 0 {
                     continue; // Skip players with unknown class
@@ -561,6 +1284,10 @@ impl Updater {
                 }
                 let last_seen = now - player_details.last_update;
                 let last_updated = now - player_details.last_update_logs;
+                let inactive = (player_details.last_raided > 0) && (now - player_details.last_raided > INACTIVE_AFTER);
+                if player_details.hidden && (last_updated < UPDATE_INTERVAL_HIDDEN) {
+                    continue; // Skip players that hid their logs, check again after a while
+                }
                 if player_details.last_update_logs == 0 {
                     let mut queue_player = player_details.clone();
                     queue_player.update_priority = 4 + player_details.priority;
@@ -569,7 +1296,7 @@ impl Updater {
                     let mut queue_player = player_details.clone();
                     queue_player.update_priority = 3 + player_details.priority;
                     self.update_queue.push(queue_player);
-                } else if (last_seen < UPDATE_INTERVAL_FAST) && ((last_updated > UPDATE_INTERVAL_FAST) || (player_details.priority > 0)) {
+                } else if !inactive && (last_seen < UPDATE_INTERVAL_FAST) && ((last_updated > UPDATE_INTERVAL_FAST) || (player_details.priority > 0)) {
                     let mut queue_player = player_details.clone();
                     queue_player.update_priority = 2 + player_details.priority;
                     self.update_queue.push(queue_player);
@@ -589,26 +1316,32 @@ impl Updater {
                 b.last_update.cmp(&a.last_update)
             }
         });
+        self.refresh_queue_costs();
     }
 
     pub fn load_config(&mut self) {
-        let mut config_path = home::home_dir().unwrap();
+        let mut config_path = self.data_dir.clone();
         config_path.push(".logtrackerapp");
-        let config_meta = fs::metadata(config_path.to_owned());
+        let config_meta = fs::metadata(&config_path);
         if config_meta.is_ok() && config_meta.unwrap().is_file() {
             let data = fs::read_to_string(config_path).unwrap();
             self.config = serde_json::from_str(data.as_str()).unwrap();
-            if let Some(gui_data_arc) = &self.gui_data_arc {
-                let gui_data = &mut gui_data_arc.lock().unwrap();
-                gui_data.game_dir = self.config.game_dir.to_string();
-                gui_data.api_id = self.config.api_id.to_string();
-                gui_data.api_secret = self.config.api_secret.to_string();
-            }
+            self.reset_api();
+        }
+        if let Some(gui_data_arc) = &self.gui_data_arc {
+            let gui_data = &mut gui_data_arc.lock().unwrap();
+            gui_data.site = self.config.site;
+            gui_data.game_dir = self.config.game_dir.to_string();
+            gui_data.api_id = self.config.api_id.to_string();
+            gui_data.api_secret = self.config.api_secret.to_string();
+            gui_data.batch_size = self.config.batch_size;
+            gui_data.partition = self.config.partition;
+            gui_data.timeframe = self.config.timeframe;
         }
     }
 
     pub fn write_config(&self) {
-        let mut config_path = home::home_dir().unwrap();
+        let mut config_path = self.data_dir.clone();
         config_path.push(".logtrackerapp");
         let mut file = File::create(config_path).unwrap();
         let data = serde_json::to_string(&self.config);
@@ -616,8 +1349,46 @@ impl Updater {
             .expect("Failed to write configuration");
     }
 
+    pub fn load_cache(&mut self) {
+        let mut cache_path = self.data_dir.clone();
+        cache_path.push(format!(".logtrackerapp_cache_{}", self.config.site.ident()));
+        if cache_path.is_file() {
+            let data = fs::read_to_string(cache_path).unwrap();
+            match serde_json::from_str(data.as_str()) {
+                Ok(cache) => self.cache = cache,
+                Err(e) => warn!("Failed to read cache: {e}")
+            }
+        }
+    }
+
+    pub fn write_cache(&self) {
+        let mut cache_path = self.data_dir.clone();
+        cache_path.push(format!(".logtrackerapp_cache_{}", self.config.site.ident()));
+        let mut file = File::create(cache_path).unwrap();
+        let data = serde_json::to_string(&self.cache);
+        file.write_all(data.unwrap().as_bytes())
+            .expect("Failed to write cache");
+    }
+
+    pub fn auth_error(&self) -> &str {
+        &self.auth_error
+    }
+
     pub fn update_gui(&self) {
+        let mut failed_realms: Vec<&str> = self.cache.realms.iter()
+            .filter(|(_realm, realm_details)| realm_details.region.is_empty())
+            .map(|(realm, _realm_details)| realm.as_str())
+            .collect();
+        failed_realms.sort();
+        let realm_error = match failed_realms.is_empty() {
+            true => String::new(),
+            false => format!("Unknown realms: {}", failed_realms.join(", "))
+        };
+        let partitions = self.partition_list();
         self.modify_gui_data(false, |gui_data| {
+            gui_data.auth_error = self.auth_error.clone();
+            gui_data.realm_error = realm_error;
+            gui_data.partitions = partitions;
             if let Some(ctx) = &gui_data.ctx {
                 ctx.request_repaint();
             }
@@ -627,155 +1398,402 @@ impl Updater {
     pub fn update_addon(&mut self) {
         let game_dir_str = String::from(self.config.game_dir.clone());
         let game_dir = PathBuf::from(game_dir_str);
-        let mut game_wtf_accounts = PathBuf::from(game_dir.clone());
+        let mut game_wtf_accounts = game_dir.clone();
         game_wtf_accounts.push("WTF");
         game_wtf_accounts.push("Account");
         if !game_wtf_accounts.is_dir() {
             return
         }
-        for account_dir in game_wtf_accounts.read_dir().expect("Failed to read WoW-Accounts!") {
-            if let Ok(account_dir) = account_dir {
-                let mut addon_lua_saved = PathBuf::from(account_dir.path());
-                addon_lua_saved.push("SavedVariables");
-                addon_lua_saved.push("LogTracker.lua");
-                if addon_lua_saved.is_file() {
-                    let addon_lua_meta = fs::metadata(addon_lua_saved.clone()).unwrap();
-                    let addon_lua_mod = addon_lua_meta.modified().unwrap();
-                    if addon_lua_mod > self.update_addon {
-                        // Reload current addon data and rebuild queue
-                        info!("Addon file {} changed! Updating...", addon_lua_saved.to_str().unwrap());
-                        self.write_addon_data();
-                        self.read_addon_data();
-                    }
+        for account_dir in game_wtf_accounts.read_dir().expect("Failed to read WoW-Accounts!").flatten() {
+            let mut addon_lua_saved = account_dir.path();
+            addon_lua_saved.push("SavedVariables");
+            addon_lua_saved.push("LogTracker.lua");
+            if addon_lua_saved.is_file() {
+                let addon_lua_meta = fs::metadata(addon_lua_saved.clone()).unwrap();
+                let addon_lua_mod = addon_lua_meta.modified().unwrap();
+                if addon_lua_mod > self.update_addon {
+                    // Reload current addon data and rebuild queue
+                    info!("Addon file {} changed! Updating...", addon_lua_saved.to_str().unwrap());
+                    self.write_addon_data();
+                    self.read_addon_data();
                 }
             }
         }
     }
 
-    pub fn update_next(&mut self) -> bool {
-        if !self.is_update_possible() {
-            sleep(Duration::new(1, 0));
-            return false;
+    pub fn selected_zones(&self) -> Vec<UpdaterZone> {
+        if !self.config.zone_ids.is_empty() {
+            // Configured zones, left out until the zone list knows their sizes
+            return self.config.zone_ids.iter()
+                .filter_map(|zone_id| self.cache.zones.iter().find(|zone| zone.id == *zone_id).cloned())
+                .collect();
         }
-        let update_index = self.update_queue_pos;
-        let update_count = self.update_queue.len();
-        if self.wcl_points_limit > 0.0 {
-            let wcl_points_left = self.wcl_points_limit - self.wcl_points_used;
-            let wcl_reserve_time = self.wcl_reset_at - Duration::new(300, 0);
-            if (wcl_points_left < 600.0) && (SystemTime::now() < wcl_reserve_time) {
-                let (prio_new, prio_update, new, update) = self.refresh_queue_status();
-                self.modify_gui_data(false, |gui_data| {
-                    let points_reserve_dt: DateTime<Local> = wcl_reserve_time.into();
-                    let points_reset_dt: DateTime<Local> = self.wcl_reset_at.into();
-                    let status_text = format!(
-                        "Priority: {} new, {} updates - Regular {} new, {} updates\nUpdated {} / {} - Reserving {} points until {} (Reset at {})",
-                        prio_new, prio_update, new, update,
-                        self.update_queue_pos, update_count, wcl_points_left.round(), points_reserve_dt.format("%R"), points_reset_dt.format("%R")
-                    );
-                    info!("Status: {}", status_text);
-                    gui_data.status_text = status_text;
-                });
-                self.update_gui();
-                return false;
+        // Raid zones of the most recent expansion first, preferring the ones that are not frozen yet
+        let mut raid_zones: Vec<&UpdaterZone> = self.cache.zones.iter()
+            .filter(|zone| zone.sizes.iter().any(|size| *size >= 10))
+            .collect();
+        raid_zones.sort_by_key(|zone| Reverse((zone.expansion_id, !zone.frozen, zone.id)));
+        raid_zones.into_iter()
+            .take(self.config.zone_count.max(1))
+            .cloned()
+            .collect()
+    }
+
+    /// Partitions of the selected zones as id and name, the first name wins for shared ids
+    pub fn partition_list(&self) -> Vec<(i64, String)> {
+        let mut partitions: Vec<(i64, String)> = Vec::new();
+        for zone in self.selected_zones() {
+            for partition in zone.partitions.iter() {
+                if !partitions.iter().any(|(id, _name)| *id == partition.id) {
+                    partitions.push((partition.id, partition.name.to_string()));
+                }
             }
         }
-        self.update_queue_pos += 1;
-        let player = self.update_queue.get(update_index).unwrap();
-        let (prio_new, prio_update, new, update) = self.refresh_queue_status();
-        if self.update_player(player.clone()) {
-            self.modify_gui_data(false, |gui_data| {
-                let status_text = format!(
-                    "Priority: {} new, {} updates - Regular {} new, {} updates\nUpdated {} / {} ({} / {} points used)",
-                    prio_new, prio_update, new, update,
-                    self.update_queue_pos, update_count, self.wcl_points_used.round(), self.wcl_points_limit.round()
-                );
-                info!("Status: {}", status_text);
-                gui_data.status_text = status_text;
-            });
-            self.update_gui();
-            return true;
-        } else {
-            self.modify_gui_data(false, |gui_data| {
-                let points_reset_dt: DateTime<Local> = self.wcl_reset_at.into();
-                let status_text = match self.wcl_points_limit {
-                    x if x == 0.0 => format!(
-                        "Priority: {} new, {} updates - Regular {} new, {} updates\nRate limit reached! Reset time is unknown",
-                        prio_new, prio_update, new, update
-                    ),
-                    _ => format!(
-                        "Priority: {} new, {} updates - Regular {} new, {} updates\nRate limit reached! Reset at {}", 
-                        prio_new, prio_update, new, update,
-                        points_reset_dt.format("%R")
-                    )
-                };
-                info!("Status: {}", status_text);
-                gui_data.status_text = status_text;
-            });
-            self.update_gui();
-            return false;
-        }
+        partitions
     }
 
-    pub fn update_player(&mut self, mut player: UpdaterPlayer) -> bool {
-        self.auth();
-        let region_name = player.realm.to_string();
-        let region = self.base_data.region_by_server_name.get(&region_name);
-        if region.is_none() {
-            return false;
-        }
-        let zone_id = 1017; // TODO: Obtain dynamically
-        let (character, character_query) = self.query_character(
-            player.name.to_string(), player.realm.to_string(), region.unwrap().to_string(), zone_id, player.class
-        );
-        if let Some(data) = character {
-            if let Some(data_char) = data.character_data.unwrap().character {
-                if data_char.class_id > 0 {
-                    player.class = data_char.class_id;
-                    let mut spec_failed = false;
-                    let base_data_class = self.base_data.classes.get(&player.class.to_string()).unwrap();
-                    for zone_size in vec![10, 25] {
-                        let ranking_id = format!("{}-{}", zone_id, zone_size);
-                        let ranking = player.ranking.entry(ranking_id).or_default();
-                        ranking.clear();
-                        for spec_index in 1..=5 {
-                            if let Some(spec_details) = base_data_class.specs.get(&spec_index.to_string()) {
-                                let data_json_opt = match (zone_size as i64, spec_index) {
-                                    (25, 1) => data_char.zone_rankings25_spec1.as_ref(),
-                                    (25, 2) => data_char.zone_rankings25_spec2.as_ref(),
-                                    (25, 3) => data_char.zone_rankings25_spec3.as_ref(),
-                                    (25, 4) => data_char.zone_rankings25_spec4.as_ref(),
-                                    (25, 5) => data_char.zone_rankings25_spec5.as_ref(),
-                                    (10, 1) => data_char.zone_rankings10_spec1.as_ref(),
-                                    (10, 2) => data_char.zone_rankings10_spec2.as_ref(),
-                                    (10, 3) => data_char.zone_rankings10_spec3.as_ref(),
-                                    (10, 4) => data_char.zone_rankings10_spec4.as_ref(),
-                                    (10, 5) => data_char.zone_rankings10_spec5.as_ref(),
-                                    _ => None
-                                };
-                                if let Some(data_json) = data_json_opt {
-                                    // Debug
-                                    /*
-                                    let mut config_path = home::home_dir().unwrap();
-                                    config_path.push("LogTrackerDebug");
-                                    config_path.push(format!("{}-{}-spec{}.json", player.name, player.realm, spec_index));
-                                    let mut file = File::create(config_path).unwrap();
-                                    file.write_all(serde_json::to_string_pretty(data_json).unwrap().as_bytes()).unwrap();
-                                    */
-                                    // ----------------
-                                    ranking.update_from_json(data_json, spec_details.id);
-                                } else {
-                                    spec_failed = true;
-                                }
+    /// Class details of the base data addon, or the ones cached from the API without it
+    fn base_data_class(&self, class_id: i64) -> Option<&UpdaterBaseDataClass> {
+        let class_ident = class_id.to_string();
+        self.base_data.classes.get(&class_ident).or_else(|| self.cache.classes.get(&class_ident))
+    }
+
+    pub fn is_classes_update_due(&self) -> bool {
+        let now = unix_now();
+        self.base_data.classes.is_empty() && (self.cache.classes.is_empty() || (now - self.cache.classes_updated >= UPDATE_INTERVAL_CLASSES))
+    }
+
+    pub fn apply_classes(&mut self, classes_response: Result<classes_view::ResponseData, UpdateError>) -> Result<(), UpdateError> {
+        let now = unix_now();
+        self.track_auth(&classes_response);
+        let classes_response = classes_response?;
+        let mut classes: HashMap<String, UpdaterBaseDataClass> = HashMap::new();
+        for class in classes_response.game_data.and_then(|game_data| game_data.classes).unwrap_or_default().into_iter().flatten() {
+            let specs: HashMap<String, UpdaterBaseDataClassSpec> = class.specs.unwrap_or_default().into_iter().flatten().enumerate()
+                .map(|(spec_index, spec)| {
+                    // The API does not know the role of a spec, healers are ranked by healing
+                    let metric = match spec.slug.as_str() {
+                        "Holy" | "Discipline" | "Restoration" | "Mistweaver" | "Preservation" => "hps",
+                        _ => "dps"
+                    };
+                    let spec_details = UpdaterBaseDataClassSpec{ id: spec.id, name: spec.name.into(), slug: spec.slug.into(), metric: metric.into() };
+                    ((spec_index + 1).to_string(), spec_details)
+                })
+                .collect();
+            classes.insert(class.id.to_string(), UpdaterBaseDataClass{ id: class.id, name: class.name.into(), slug: class.slug.into(), specs });
+        }
+        info!("Using {} classes from the API", classes.len());
+        self.cache.classes = classes;
+        self.cache.classes_updated = now;
+        self.write_cache();
+        Ok(())
+    }
+
+    pub fn is_zone_update_due(&self) -> bool {
+        let now = unix_now();
+        self.cache.zones.is_empty() || (now - self.cache.zones_updated >= UPDATE_INTERVAL_ZONES)
+            || (self.cache.zones_version != ZONES_VERSION)
+    }
+
+    pub fn apply_zones(&mut self, zones_response: Result<zones_view::ResponseData, UpdateError>) -> Result<(), UpdateError> {
+        let now = unix_now();
+        self.track_auth(&zones_response);
+        let zones_response = zones_response?;
+        {
+            let mut zones: Vec<UpdaterZone> = Vec::new();
+            for expansion in zones_response.world_data.and_then(|world_data| world_data.expansions).unwrap_or_default().into_iter().flatten() {
+                for zone in expansion.zones.unwrap_or_default().into_iter().flatten() {
+                    let mut sizes: Vec<i64> = Vec::new();
+                    let mut difficulties: Vec<(i64, Vec<i64>)> = Vec::new();
+                    for difficulty in zone.difficulties.unwrap_or_default().into_iter().flatten() {
+                        let difficulty_sizes: Vec<i64> = difficulty.sizes.unwrap_or_default().into_iter().flatten().collect();
+                        for size in difficulty_sizes.iter() {
+                            if !sizes.contains(size) {
+                                sizes.push(*size);
                             }
-                        }                        
-                    }
-                    // Output debug if some spec failed
-                    if spec_failed {
-                        if let Some(character_json) = &character_query {
-                            info!("No result for query: {}", character_json);
                         }
+                        difficulties.push((difficulty.id, difficulty_sizes));
+                    }
+                    sizes.sort();
+                    let encounters: Vec<i64> = zone.encounters.unwrap_or_default().into_iter().flatten()
+                        .map(|encounter| encounter.id)
+                        .collect();
+                    let partitions: Vec<UpdaterPartition> = zone.partitions.unwrap_or_default().into_iter().flatten()
+                        .map(|partition| UpdaterPartition{ id: partition.id, name: partition.name.into(), default: partition.default })
+                        .collect();
+                    zones.push(UpdaterZone{
+                        id: zone.id, name: zone.name.into(), expansion_id: expansion.id, frozen: zone.frozen, sizes,
+                        difficulties, encounters, partitions
+                    });
+                }
+            }
+            self.cache.zones = zones;
+            self.cache.zones_updated = now;
+            self.cache.zones_version = ZONES_VERSION;
+            self.write_cache();
+            for zone in self.selected_zones() {
+                info!("Using zone {} ({})", zone.name, zone.id);
+            }
+            for zone_id in self.config.zone_ids.iter().filter(|zone_id| !self.cache.zones.iter().any(|zone| zone.id == **zone_id)) {
+                warn!("Configured zone {} is not in the zone list", zone_id);
+            }
+        }
+        Ok(())
+    }
+
+    fn query_cost_key(query: &CharacterQuery) -> String {
+        format!("{}|{}", query.shape(), query.spec_count())
+    }
+
+    fn is_cost_sample_due(&self, cost_key: &str) -> bool {
+        let now = unix_now();
+        match self.cache.query_costs.get(cost_key) {
+            Some(cost) => (cost.samples < COST_SAMPLES_MIN) || (now - cost.last_sample > COST_SAMPLE_INTERVAL),
+            None => true
+        }
+    }
+
+    fn record_query_cost(&mut self, cost_key: &str, points: f64) {
+        let now = unix_now();
+        let cost = self.cache.query_costs.entry(cost_key.to_string()).or_default();
+        // Running average, switching to a moving average once enough samples are known
+        cost.samples += 1;
+        cost.points += (points - cost.points) / (cost.samples.min(COST_SAMPLES_MIN * 2) as f64);
+        cost.last_sample = now;
+        info!("Query cost for {}: {:.2} points (average {:.2} over {} samples)", cost_key, points, cost.points, cost.samples);
+        self.write_cache();
+        self.refresh_queue_costs();
+    }
+
+    pub fn estimate_query_cost(&self, query: &CharacterQuery) -> Option<f64> {
+        if let Some(cost) = self.cache.query_costs.get(&Updater::query_cost_key(query)) {
+            return Some(cost.points);
+        }
+        // Unknown shape, use the average cost per spec of all known shapes
+        let (points, specs) = self.cache.query_costs.iter()
+            .filter_map(|(cost_key, cost)| {
+                let spec_count: f64 = cost_key.rsplit('|').next()?.parse().ok()?;
+                Some((cost.points, spec_count.max(1.0)))
+            })
+            .fold((0.0, 0.0), |(points, specs), (cost_points, cost_specs)| (points + cost_points, specs + cost_specs));
+        if specs > 0.0 {
+            Some(points / specs * (query.spec_count().max(1) as f64))
+        } else {
+            None
+        }
+    }
+
+    /// Sums up the estimated cost of the queue, cleared if any cost is still unknown.
+    fn refresh_queue_costs(&mut self) {
+        let zones = self.selected_zones();
+        let mut queue_costs = vec![(0.0, 0)];
+        for player in self.update_queue.iter() {
+            let (points, players) = queue_costs[queue_costs.len() - 1];
+            match self.character_query(player, &zones) {
+                Ok(query) => match self.estimate_query_cost(&query) {
+                    Some(cost) => queue_costs.push((points + cost, players + 1)),
+                    None => {
+                        self.update_queue_costs.clear();
+                        return;
+                    }
+                },
+                Err(_) => queue_costs.push((points, players))
+            }
+        }
+        self.update_queue_costs = queue_costs;
+    }
+
+    /// Players of the queue that can still be updated before the points left run into the reserve
+    pub fn forecast_players(&self) -> Option<usize> {
+        if self.wcl_points_limit <= 0.0 {
+            return None;
+        }
+        let (points_start, players_start) = *self.update_queue_costs.get(self.update_queue_pos)?;
+        let points_left = self.wcl_points_limit - self.wcl_points_used - self.points_in_flight - POINTS_RESERVE;
+        if points_left < 0.0 {
+            return Some(0);
+        }
+        let forecast_end = self.update_queue_costs.partition_point(|(points, _players)| *points - points_start <= points_left);
+        let (_points_end, players_end) = self.update_queue_costs[forecast_end.max(1) - 1];
+        Some(players_end.saturating_sub(players_start))
+    }
+
+    pub fn max_in_flight(&self) -> usize {
+        match self.config.max_in_flight {
+            0 => MAX_IN_FLIGHT,
+            max_in_flight => max_in_flight
+        }
+    }
+
+    pub fn requests_in_flight(&self) -> usize {
+        self.requests_in_flight
+    }
+
+    pub fn points_used(&self) -> f64 {
+        self.wcl_points_used
+    }
+
+    /// Estimated points of a request for the given players, if the cost of every query is known.
+    fn estimate_players_cost(&self, players: &[UpdaterPlayer], zones: &[UpdaterZone]) -> Option<f64> {
+        players.iter()
+            .filter_map(|player| self.character_query(player, zones).ok())
+            .map(|query| self.estimate_query_cost(&query))
+            .sum()
+    }
+
+    /// Takes the next batch from the queue, as long as the points left cover it next to the
+    /// requests already in flight. Returns `None` if nothing can be sent right now.
+    pub fn prepare_next(&mut self) -> Result<Option<UpdaterRequest>, UpdateError> {
+        if !self.is_update_possible() || self.cost_sampling {
+            return Ok(None);
+        }
+        let update_index = self.update_queue_pos;
+        let update_count = self.update_queue.len();
+        // Mixed batches can't be sampled, so learn the cost of a single character first
+        let batch_size = match self.cache.query_costs.is_empty() {
+            true => 1,
+            false => self.config.batch_size.max(1)
+        };
+        let batch_end = update_count.min(update_index + batch_size);
+        if self.wcl_points_limit > 0.0 {
+            let wcl_points_left = self.wcl_points_limit - self.wcl_points_used - self.points_in_flight;
+            let wcl_reserve_time = self.wcl_reset_at - Duration::new(300, 0);
+            if (wcl_points_left < POINTS_RESERVE) && (SystemTime::now() < wcl_reserve_time) {
+                if self.requests_in_flight > 0 {
+                    return Ok(None);
+                }
+                let (prio_new, prio_update, new, update) = self.refresh_queue_status();
+                self.modify_gui_data(false, |gui_data| {
+                    let points_reserve_dt: DateTime<Local> = wcl_reserve_time.into();
+                    let points_reset_dt: DateTime<Local> = self.wcl_reset_at.into();
+                    let status_text = format!(
+                        "Priority: {} new, {} updates - Regular {} new, {} updates\nUpdated {} / {} - Reserving {} points until {} (Reset at {})",
+                        prio_new, prio_update, new, update,
+                        self.update_queue_pos, update_count, wcl_points_left.round(), points_reserve_dt.format("%R"), points_reset_dt.format("%R")
+                    );
+                    info!("Status: {}", status_text);
+                    gui_data.status_text = status_text;
+                });
+                self.update_gui();
+                return Err(UpdateError::RateLimited(Some(wcl_reserve_time)));
+            }
+            if self.requests_in_flight > 0 {
+                // Only run requests in parallel if their cost is known to fit into the budget
+                let zones = self.selected_zones();
+                match self.estimate_players_cost(&self.update_queue[update_index..batch_end], &zones) {
+                    Some(points) if wcl_points_left - points >= POINTS_RESERVE => {},
+                    _ => return Ok(None)
+                }
+            }
+        } else if self.requests_in_flight > 0 {
+            return Ok(None);
+        }
+        let players = self.update_queue[update_index..batch_end].to_vec();
+        self.update_queue_pos = batch_end;
+        Ok(Some(self.prepare_request(players)))
+    }
+
+    /// Builds the query for the given players and books its estimated cost as in flight
+    /// until the response is applied.
+    pub fn prepare_request(&mut self, players: Vec<UpdaterPlayer>) -> UpdaterRequest {
+        let zones = self.selected_zones();
+        let mut results: Vec<Result<(), UpdateError>> = Vec::new();
+        let mut batch: Vec<(usize, UpdaterPlayer)> = Vec::new();
+        let mut queries: Vec<CharacterQuery> = Vec::new();
+        for player in players {
+            match self.character_query(&player, &zones) {
+                Ok(query) => {
+                    batch.push((results.len(), player));
+                    queries.push(query);
+                    results.push(Ok(()));
+                },
+                Err(e) => {
+                    warn!("Skipping {}-{}: {}", player.name, player.realm, e);
+                    results.push(Err(e));
+                }
+            }
+        }
+        let mut request = UpdaterRequest{
+            api: Some(self.api.clone()),
+            api_generation: self.api_generation,
+            document: CharacterQuery::document(&queries),
+            zones, batch, results,
+            ..Default::default()
+        };
+        if request.is_empty() {
+            return request;
+        }
+        // Sample the point cost of the query if all characters share the same shape and nothing else is running
+        request.cost_key = Updater::query_cost_key(&queries[0]);
+        request.cost_estimate = queries.iter().filter_map(|query| self.estimate_query_cost(query)).sum();
+        request.cost_sample = (self.requests_in_flight == 0) && self.is_cost_sample_due(&request.cost_key)
+            && queries.iter().all(|query| Updater::query_cost_key(query) == request.cost_key);
+        self.requests_in_flight += 1;
+        self.points_in_flight += request.cost_estimate;
+        self.cost_sampling |= request.cost_sample;
+        request
+    }
+
+    pub fn record_request_cost(&mut self, request: &UpdaterRequest, points_before: f64) {
+        if request.cost_sample && (self.wcl_points_used >= points_before) {
+            self.record_query_cost(&request.cost_key, (self.wcl_points_used - points_before) / (request.batch.len() as f64));
+        }
+    }
+
+    fn track_auth<T>(&mut self, result: &Result<T, UpdateError>) {
+        match result {
+            Ok(_) if !self.auth_error.is_empty() => {
+                self.auth_error.clear();
+                self.update_gui();
+            },
+            Err(e @ UpdateError::Auth(_)) => {
+                self.auth_error = e.to_string();
+                self.update_gui();
+            },
+            _ => {}
+        }
+    }
+
+    /// Writes the response of a prepared request into the player list.
+    pub fn apply_request(&mut self, request: UpdaterRequest, response: Result<Response<serde_json::Value>, UpdateError>) -> Result<Vec<Result<(), UpdateError>>, UpdateError> {
+        if request.is_empty() {
+            return Ok(request.results);
+        }
+        self.requests_in_flight = self.requests_in_flight.saturating_sub(1);
+        self.points_in_flight = (self.points_in_flight - request.cost_estimate).max(0.0);
+        if request.cost_sample {
+            self.cost_sampling = false;
+        }
+        self.track_auth(&response);
+        let characters = response?;
+        if request.api_generation != self.api_generation {
+            info!("Discarding response for a previous site or account");
+            return Ok(request.results);
+        }
+        // Keep track of the spent points until the next rate limit update, sampled requests already read them
+        if !request.cost_sample {
+            self.wcl_points_used += request.cost_estimate;
+        }
+        let UpdaterRequest{ zones, batch, mut results, document, .. } = request;
+        for (character_index, (result_index, mut player)) in batch.into_iter().enumerate() {
+            let player_ident = format!("{}-{}", player.name, player.realm);
+            let result = match &characters.data {
+                Some(data) => {
+                    let data_char = &data["characterData"][CharacterQuery::character_alias(character_index)];
+                    if data_char.is_null() {
+                        Err(UpdateError::CharacterNotFound(player_ident.clone()))
+                    } else {
+                        self.update_player_rankings(&mut player, &zones, data_char, &document)
                     }
+                },
+                None => {
+                    // Request successful, but no logs available
+                    let messages: Vec<String> = characters.errors.iter().flatten().map(|error| error.message.clone()).collect();
+                    Err(UpdateError::Parse(format!("No data for {}: {}", player_ident, messages.join(", "))))
                 }
+            };
+            if let Err(e) = &result {
+                warn!("Update of {} incomplete: {}", player_ident, e);
             }
             // Debug
             /*
@@ -783,34 +1801,424 @@ impl Updater {
             config_path.push("LogTrackerDebug");
             config_path.push(format!("{}-{}-query.json", player.name, player.realm));
             let mut file = File::create(config_path).unwrap();
-            file.write_all(character_query.unwrap().as_bytes()).unwrap();
+            file.write_all(document.as_bytes()).unwrap();
             */
             // ----------------
-            player.last_update = i64::try_from(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()).unwrap();
+            player.last_update = unix_now();
             player.last_update_logs = player.last_update;
+            self.merge_renamed(&mut player);
             // Write into player list
-            let realm_players = self.players.entry(player.realm.to_string()).or_insert_with(|| HashMap::new());
+            let realm_players = self.players.entry(player.realm.to_string()).or_default();
             realm_players.insert(player.name.to_string(), player);
+            results[result_index] = result;
+        }
+        Ok(results)
+    }
+
+    /// Whether a saved entry last updated at the given time is left over from a rename or transfer
+    fn is_merged_away(&self, realm: &str, name: &str, last_update: i64) -> bool {
+        self.cache.renames.get(&format!("{}-{}", name, realm)).is_some_and(|rename| last_update <= rename.merged)
+    }
+
+    /// Merges and drops entries sharing the canonical id of the player, which are left over
+    /// from a rename or realm transfer.
+    fn merge_renamed(&mut self, player: &mut UpdaterPlayer) {
+        if player.canonical_id == 0 {
+            return;
+        }
+        let previous_keys: Vec<(String, String)> = self.players.iter()
+            .flat_map(|(realm, player_list)| player_list.values().map(move |previous| (realm, previous)))
+            .filter(|(_realm, previous)| (previous.canonical_id == player.canonical_id) && ((previous.realm != player.realm) || (previous.name != player.name)))
+            .map(|(realm, previous)| (realm.clone(), previous.name.to_string()))
+            .collect();
+        if previous_keys.is_empty() {
+            return;
+        }
+        let player_ident = format!("{}-{}", player.name, player.realm);
+        for (realm, name) in previous_keys {
+            let Some(previous) = self.players.get_mut(&realm).and_then(|player_list| player_list.remove(&name)) else {
+                continue;
+            };
+            let previous_ident = format!("{}-{}", name, realm);
+            info!("{} was renamed or transferred to {}, merging", previous_ident, player_ident);
+            player.merge_history(previous);
+            let update_queue_pos = self.update_queue_pos.min(self.update_queue.len());
+            let mut pending = self.update_queue.split_off(update_queue_pos);
+            pending.retain(|pending_player| (pending_player.realm.as_ref() != realm) || (pending_player.name.as_ref() != name));
+            self.update_queue.extend(pending);
+            self.cache.renames.insert(previous_ident, UpdaterRename{ ident: player_ident.clone(), merged: unix_now() });
+        }
+        // Renamed back to a previous name
+        self.cache.renames.remove(&player_ident);
+        self.write_cache();
+        self.refresh_queue_costs();
+    }
+
+    pub fn set_status_text(&self, status_text: &str) {
+        self.modify_gui_data(false, |gui_data| {
+            gui_data.status_text = status_text.to_string();
+        });
+    }
+
+    pub fn report_batch(&self, result: &Result<(), UpdateError>) {
+        let update_count = self.update_queue.len();
+        let (prio_new, prio_update, new, update) = self.refresh_queue_status();
+        match result {
+            Ok(()) => {
+                let forecast = match self.forecast_players() {
+                    Some(forecast_players) => {
+                        let points_reset_dt: DateTime<Local> = self.wcl_reset_at.into();
+                        format!(" - ~{} more until {}", forecast_players, points_reset_dt.format("%R"))
+                    },
+                    None => String::new()
+                };
+                self.modify_gui_data(false, |gui_data| {
+                    let status_text = format!(
+                        "Priority: {} new, {} updates - Regular {} new, {} updates\nUpdated {} / {} ({} / {} points used, {} in flight){}",
+                        prio_new, prio_update, new, update,
+                        self.update_queue_pos, update_count, self.wcl_points_used.round(), self.wcl_points_limit.round(), self.requests_in_flight, forecast
+                    );
+                    info!("Status: {}", status_text);
+                    gui_data.status_text = status_text;
+                });
+            },
+            Err(UpdateError::RateLimited(_)) => {
+                self.modify_gui_data(false, |gui_data| {
+                    let points_reset_dt: DateTime<Local> = self.wcl_reset_at.into();
+                    let status_text = match self.wcl_points_limit {
+                        0.0 => format!(
+                            "Priority: {} new, {} updates - Regular {} new, {} updates\nRate limit reached! Reset time is unknown",
+                            prio_new, prio_update, new, update
+                        ),
+                        _ => format!(
+                            "Priority: {} new, {} updates - Regular {} new, {} updates\nRate limit reached! Reset at {}", 
+                            prio_new, prio_update, new, update,
+                            points_reset_dt.format("%R")
+                        )
+                    };
+                    info!("Status: {}", status_text);
+                    gui_data.status_text = status_text;
+                });
+            },
+            Err(e) => {
+                self.modify_gui_data(false, |gui_data| {
+                    let status_text = format!(
+                        "Priority: {} new, {} updates - Regular {} new, {} updates\nUpdated {} / {} - {}",
+                        prio_new, prio_update, new, update,
+                        self.update_queue_pos, update_count, e
+                    );
+                    warn!("Status: {}", status_text);
+                    gui_data.status_text = status_text;
+                });
+            }
+        }
+        self.update_gui();
+    }
+
+    fn update_player_profile(&self, player: &mut UpdaterPlayer, data_char: &serde_json::Value) {
+        if let Some(level) = data_char["level"].as_i64().filter(|level| *level > 0) {
+            player.level = level;
+        }
+        if let Some(faction) = data_char["faction"]["name"].as_str().filter(|faction| !faction.is_empty()) {
+            player.faction = faction.into();
+        }
+        player.hidden = data_char["hidden"].as_bool().unwrap_or(false);
+        player.guild = UpdaterPlayerGuild::from_json(data_char);
+        if let Some(canonical_id) = data_char["canonicalID"].as_i64().or_else(|| data_char["id"].as_i64()) {
+            player.canonical_id = canonical_id;
+        }
+        let report_times = data_char["recentReports"]["data"].as_array().into_iter().flatten()
+            .filter_map(|report| report["startTime"].as_f64());
+        if let Some(start_time) = report_times.reduce(f64::max) {
+            // Report times are in milliseconds
+            player.last_raided = player.last_raided.max((start_time / 1000.0) as i64);
+        }
+        player.profile_synced = true;
+    }
+
+    fn update_player_rankings(&self, player: &mut UpdaterPlayer, zones: &[UpdaterZone], data_char: &serde_json::Value, character_query: &str) -> Result<(), UpdateError> {
+        let class_id = data_char["classID"].as_i64()
+            .ok_or_else(|| UpdateError::Parse(format!("Missing class for {}-{}", player.name, player.realm)))?;
+        self.update_player_profile(player, data_char);
+        if player.hidden {
+            // Rankings of hidden characters are not available, keep the previous ones
+            info!("Logs of {}-{} are hidden", player.name, player.realm);
+            return Ok(());
+        }
+        if class_id > 0 {
+            player.class = class_id;
+            let mut spec_failed = false;
+            let base_data_class = self.base_data_class(player.class)
+                .ok_or(UpdateError::UnknownClass(class_id))?;
+            for zone in zones.iter() {
+                for (zone_size, partition, timeframe) in zone.query_rankings(self.config.partition, self.config.timeframe) {
+                    let ranking = player.ranking.entry(zone.ranking_key(zone_size, partition, timeframe)).or_default();
+                    ranking.clear();
+                    for (spec_index, spec_details) in base_data_class.query_specs() {
+                        let data_json = &data_char[CharacterQuery::alias(zone.id, zone_size, partition, timeframe, spec_index)];
+                        if !data_json.is_null() {
+                            // Debug
+                            /*
+                            let mut config_path = home::home_dir().unwrap();
+                            config_path.push("LogTrackerDebug");
+                            config_path.push(format!("{}-{}-spec{}.json", player.name, player.realm, spec_index));
+                            let mut file = File::create(config_path).unwrap();
+                            file.write_all(serde_json::to_string_pretty(data_json).unwrap().as_bytes()).unwrap();
+                            */
+                            // ----------------
+                            ranking.update_from_json(data_json, spec_details.id, self.query_character_metric(spec_details));
+                            if self.config.bracket_rankings {
+                                let data_json = &data_char[CharacterQuery::bracket_alias(zone.id, zone_size, partition, timeframe, spec_index)];
+                                ranking.update_bracket_from_json(data_json, spec_details.id);
+                            }
+                        } else {
+                            spec_failed = true;
+                        }
+                    }
+                }
+            }
+            // Output debug if some spec failed
+            if spec_failed {
+                info!("No result for {}-{} in query: {}", player.name, player.realm, character_query);
+            }
+        }
+        let encounter_class = self.base_data_class(player.class).filter(|_| self.config.encounter_rankings);
+        if let Some(base_data_class) = encounter_class {
+            for zone in zones.iter() {
+                for encounter_id in zone.encounters.iter() {
+                    for (difficulty, zone_size) in zone.query_difficulties() {
+                        player.boss_rankings.retain(|boss_ranking| {
+                            (boss_ranking.encounter_id, boss_ranking.difficulty, boss_ranking.size) != (*encounter_id, difficulty, zone_size)
+                        });
+                        for (spec_index, spec_details) in base_data_class.query_specs() {
+                            let data_json = &data_char[CharacterQuery::encounter_alias(*encounter_id, difficulty, zone_size, spec_index)];
+                            let metric = self.query_character_metric(spec_details);
+                            if let Some(boss_ranking) = UpdaterBossRanking::from_json(*encounter_id, difficulty, zone_size, spec_details.id, metric, data_json) {
+                                player.boss_rankings.push(boss_ranking);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Region and slug of a realm, from the base data or previous lookups
+    pub fn realm_lookup(&self, realm: &str) -> Option<(String, String)> {
+        if let Some(region) = self.base_data.region_by_server_name.get(realm) {
+            return Some((region.clone(), slug::server_slug(realm)));
+        }
+        self.cache.realms.get(realm)
+            .filter(|realm_details| !realm_details.region.is_empty())
+            .map(|realm_details| (realm_details.region.to_string(), realm_details.slug.to_string()))
+    }
+
+    /// A realm of the pending queue that is neither known nor failed recently
+    pub fn next_unresolved_realm(&self) -> Option<String> {
+        let now = unix_now();
+        self.update_queue.iter().skip(self.update_queue_pos)
+            .map(|player| player.realm.as_ref())
+            .filter(|realm| self.realm_lookup(realm).is_none())
+            .find(|realm| self.cache.realms.get(*realm).is_none_or(|realm_details| now - realm_details.updated >= UPDATE_INTERVAL_REALMS))
+            .map(|realm| realm.to_string())
+    }
+
+    /// API handle and document of a realm lookup, page 0 tries the slug derived from the name
+    /// and the following pages go through the server lists.
+    pub fn prepare_realm_lookup(&self, realm: &str, page: i64) -> (WclApi, String) {
+        let document = match page {
+            0 => RealmQuery::server_document(&slug::server_slug(realm)),
+            _ => RealmQuery::servers_document(REALM_SERVERS_PAGE, page)
+        };
+        (self.api.clone(), document)
+    }
+
+    /// Stores the region and slug if the response knows the realm. Returns whether the next
+    /// page should be looked up, or an unknown realm error once there are no pages left.
+    pub fn apply_realm_lookup(&mut self, realm: &str, page: i64, response: Result<Response<serde_json::Value>, UpdateError>) -> Result<bool, UpdateError> {
+        self.track_auth(&response);
+        let response = response?;
+        let data_world = response.data.as_ref().map(|data| &data["worldData"]).unwrap_or(&serde_json::Value::Null);
+        let realm_name = realm.to_lowercase();
+        let mut servers: Vec<(String, &serde_json::Value)> = Vec::new();
+        let mut more_pages = false;
+        if page == 0 {
+            for region in RealmQuery::REGIONS {
+                servers.push((region.to_string(), &data_world[RealmQuery::region_alias(region)]));
+            }
+            more_pages = true;
         } else {
-            if !self.update_api_limit() {
-                return false;
+            for data_region in data_world["regions"].as_array().into_iter().flatten() {
+                let region = data_region["slug"].as_str().unwrap_or_default().to_uppercase();
+                let data_servers = &data_region["servers"];
+                for data_server in data_servers["data"].as_array().into_iter().flatten() {
+                    servers.push((region.clone(), data_server));
+                }
+                more_pages |= data_servers["has_more_pages"].as_bool().unwrap_or(false);
             }
-            // Request successful, but no logs available
-            player.last_update = i64::try_from(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()).unwrap();
-            player.last_update_logs = player.last_update;
-            // Write into player list
-            let realm_players = self.players.entry(player.realm.to_string()).or_insert_with(|| HashMap::new());
-            realm_players.insert(player.name.to_string(), player);
-            return true;
         }
-        true
+        let now = unix_now();
+        let found = servers.into_iter().find(|(_region, data_server)| {
+            data_server["name"].as_str().is_some_and(|server_name| server_name.to_lowercase() == realm_name)
+        });
+        if let Some((region, data_server)) = found {
+            let slug = data_server["slug"].as_str().unwrap_or_default();
+            info!("Found realm {} in region {} as {}", realm, region, slug);
+            self.cache.realms.insert(realm.to_string(), UpdaterRealm{ region: region.into(), slug: slug.into(), updated: now });
+            self.write_cache();
+            self.update_gui();
+            return Ok(false);
+        }
+        if more_pages && (page < REALM_SERVERS_PAGES_MAX) {
+            return Ok(true);
+        }
+        warn!("Realm {} not found", realm);
+        self.cache.realms.insert(realm.to_string(), UpdaterRealm{ updated: now, ..Default::default() });
+        self.write_cache();
+        self.update_gui();
+        Err(UpdateError::UnknownRealm(realm.to_string()))
+    }
+
+    /// API handle and document for a page of the members of a guild on the given realm
+    pub fn prepare_guild_members(&self, realm: &str, guild_name: &str, page: i64) -> Result<(WclApi, String), UpdateError> {
+        let (region, server_slug) = self.realm_lookup(realm)
+            .ok_or_else(|| UpdateError::UnknownRealm(realm.to_string()))?;
+        Ok((self.api.clone(), GuildQuery::members_document(guild_name, &server_slug, &region, GUILD_MEMBERS_PAGE, page)))
+    }
+
+    /// Adds the members of a page to the players and the update queue, returns the number of
+    /// members queued and whether more pages are available.
+    pub fn apply_guild_members(&mut self, realm: &str, guild_name: &str, priority: i64, page: i64, response: Result<Response<serde_json::Value>, UpdateError>) -> Result<(usize, bool), UpdateError> {
+        self.track_auth(&response);
+        let response = response?;
+        let Some(data) = response.data else {
+            let messages: Vec<String> = response.errors.iter().flatten().map(|error| error.message.clone()).collect();
+            return Err(UpdateError::Parse(format!("No data for guild {}-{}: {}", guild_name, realm, messages.join(", "))));
+        };
+        let data_members = &data["guildData"]["guild"]["members"];
+        if data_members.is_null() {
+            return Err(UpdateError::GuildNotFound(format!("{}-{}", guild_name, realm)));
+        }
+        let mut queued: Vec<UpdaterPlayer> = Vec::new();
+        for data_member in data_members["data"].as_array().into_iter().flatten() {
+            let Some(name) = data_member["name"].as_str() else {
+                continue;
+            };
+            let member_realm = data_member["server"]["name"].as_str().unwrap_or(realm);
+            let player = self.get_player(member_realm, name);
+            if let Some(class_id) = data_member["classID"].as_i64().filter(|class_id| *class_id > 0) {
+                player.class = class_id;
+            }
+            if let Some(level) = data_member["level"].as_i64().filter(|level| *level > 0) {
+                player.level = level;
+            }
+            let mut queue_player = player.clone();
+            queue_player.update_priority = 4 + priority;
+            queued.push(queue_player);
+        }
+        // Replace pending entries of the members and keep the queue ordered by priority
+        let update_queue_pos = self.update_queue_pos.min(self.update_queue.len());
+        let mut pending = self.update_queue.split_off(update_queue_pos);
+        pending.retain(|pending_player| !queued.iter().any(|player| (player.realm == pending_player.realm) && (player.name == pending_player.name)));
+        let queued_count = queued.len();
+        pending.extend(queued);
+        pending.sort_by_key(|pending_player| Reverse(pending_player.update_priority));
+        self.update_queue.extend(pending);
+        self.refresh_queue_costs();
+        let more_pages = data_members["has_more_pages"].as_bool().unwrap_or(false) && (page < GUILD_MEMBERS_PAGES_MAX);
+        Ok((queued_count, more_pages))
+    }
+
+    /// Guilds of known players without recent progress data, up to one batch
+    pub fn prepare_guilds(&self) -> Option<UpdaterGuildRequest> {
+        if !self.config.guild_progress {
+            return None;
+        }
+        let now = unix_now();
+        let mut guilds: Vec<i64> = self.players.values()
+            .flat_map(|player_list| player_list.values())
+            .filter_map(|player| player.guild.as_ref().map(|guild| guild.id))
+            .filter(|guild_id| self.cache.guilds.get(guild_id).is_none_or(|guild| now - guild.updated >= UPDATE_INTERVAL_GUILDS))
+            .collect();
+        guilds.sort();
+        guilds.dedup();
+        guilds.truncate(self.config.batch_size.max(1));
+        if guilds.is_empty() {
+            return None;
+        }
+        let zones = self.selected_zones();
+        if zones.is_empty() {
+            return None;
+        }
+        let queries: Vec<GuildQuery> = guilds.iter()
+            .map(|guild_id| zones.iter().fold(GuildQuery::new(*guild_id), |query, zone| query.zone(zone.id, zone.query_sizes())))
+            .collect();
+        Some(UpdaterGuildRequest{
+            api: Some(self.api.clone()),
+            api_generation: self.api_generation,
+            document: GuildQuery::document(&queries),
+            zones, guilds
+        })
     }
 
-    pub fn update_api_limit(&mut self) -> bool {
-        self.auth();
-        let rate_limit = self.query_rate_limit();
-        if let Some(rate_limit_response) = rate_limit {
-            let rate_limit_data = rate_limit_response.rate_limit_data.unwrap();
+    pub fn apply_guilds(&mut self, request: UpdaterGuildRequest, response: Result<Response<serde_json::Value>, UpdateError>) -> Result<(), UpdateError> {
+        self.track_auth(&response);
+        let response = response?;
+        if request.api_generation != self.api_generation {
+            info!("Discarding guild response for a previous site or account");
+            return Ok(());
+        }
+        let Some(data) = &response.data else {
+            // Nothing came back, the guilds are requested again
+            let messages: Vec<String> = response.errors.iter().flatten().map(|error| error.message.clone()).collect();
+            return Err(UpdateError::Parse(format!("No guild data: {}", messages.join(", "))));
+        };
+        let has_errors = response.errors.as_ref().is_some_and(|errors| !errors.is_empty());
+        let now = unix_now();
+        for (guild_index, guild_id) in request.guilds.iter().enumerate() {
+            let data_guild = &data["guildData"][GuildQuery::guild_alias(guild_index)];
+            if data_guild.is_null() && has_errors {
+                continue; // Possibly failed instead of unknown, try again later
+            }
+            // Unknown guilds are marked as updated as well, so they are not requested over and over
+            let guild = self.cache.guilds.entry(*guild_id).or_default();
+            guild.updated = now;
+            if data_guild.is_null() {
+                continue;
+            }
+            guild.name = data_guild["name"].as_str().unwrap_or_default().into();
+            guild.realm = data_guild["server"]["name"].as_str().unwrap_or_default().into();
+            for zone in request.zones.iter() {
+                for zone_size in zone.query_sizes() {
+                    let data_progress = &data_guild[GuildQuery::zone_alias(zone.id)][GuildQuery::progress_alias(zone_size)];
+                    let rank = |rank_type: &str| data_progress[rank_type]["number"].as_i64().unwrap_or_default();
+                    guild.ranks.insert(format!("{}-{}", zone.id, zone_size), (rank("worldRank"), rank("regionRank"), rank("serverRank")));
+                }
+            }
+        }
+        self.write_cache();
+        Ok(())
+    }
+
+    /// Guild progress as `LogTracker_AppGuildData`, by realm and guild name
+    fn guild_export(&self) -> String {
+        let mut realms: HashMap<&str, Vec<String>> = HashMap::new();
+        for guild in self.cache.guilds.values().filter(|guild| !guild.name.is_empty()) {
+            let ranks: Vec<String> = guild.ranks.iter()
+                .map(|(zone_ident, (world, region, server))| format!("[\"{}\"] = {{{},{},{}}}", zone_ident, world, region, server))
+                .collect();
+            realms.entry(&guild.realm).or_default().push(format!("    [{}] = {{ {} }}", lua_string(&guild.name), ranks.join(",")));
+        }
+        let realms: Vec<String> = realms.iter()
+            .map(|(realm, guilds)| format!("  [{}] = {{\n{}\n  }}", lua_string(realm), guilds.join(",\n")))
+            .collect();
+        format!("\nLogTracker_AppGuildData = {{\n{}\n}}", realms.join(",\n"))
+    }
+
+    pub fn apply_api_limit(&mut self, rate_limit_response: Result<rate_limit_view::ResponseData, UpdateError>) -> Result<(), UpdateError> {
+        self.track_auth(&rate_limit_response);
+        if let Some(rate_limit_data) = rate_limit_response?.rate_limit_data {
             info!(
                 "Rate limit info: {} / {} points spent, reset in {} seconds", 
                 rate_limit_data.points_spent_this_hour, rate_limit_data.limit_per_hour, rate_limit_data.points_reset_in
@@ -818,125 +2226,731 @@ impl Updater {
             self.wcl_points_limit = rate_limit_data.limit_per_hour as f64;
             self.wcl_points_used = rate_limit_data.points_spent_this_hour;
             self.wcl_reset_at = SystemTime::now() + Duration::new(u64::try_from(rate_limit_data.points_reset_in).unwrap_or_default() + 60, 0);
-            true
+            Ok(())
         } else {
-            false
+            Err(UpdateError::Parse("Missing rate limit data".to_string()))
         }
     }
 
-    fn auth(&mut self) -> String {
-        if !self.wcl_token.is_empty() {
-            return self.wcl_token.clone();
+    /// Ranking metric of a spec, the override if it is valid, otherwise the base data metric or dps
+    pub fn query_character_metric(&self, spec: &UpdaterBaseDataClassSpec) -> &'static str {
+        let valid_metric = |metric_str: &str| RANKING_METRICS.iter().find(|metric| **metric == metric_str).copied();
+        if let Some(metric_override) = self.config.metric_overrides.get(&spec.id) {
+            match valid_metric(metric_override) {
+                Some(metric) => return metric,
+                None => warn!("Ignoring unknown metric {} for spec {}", metric_override, spec.id)
+            }
         }
-        let client = BasicClient::new(
-            ClientId::new(self.config.api_id.to_string()),
-            Some(ClientSecret::new(self.config.api_secret.to_string())),
-            AuthUrl::new("https://www.warcraftlogs.com/oauth/authorize".to_string()).unwrap(),
-            Some(TokenUrl::new("https://www.warcraftlogs.com/oauth/token".to_string()).unwrap()),
-        );
-        
-        let token_result: StandardTokenResponse<EmptyExtraTokenFields, BasicTokenType> = client
-            .exchange_client_credentials()
-            .request(http_client).unwrap();
-        let mut auth_string: String = "Bearer ".to_string();
-        auth_string.push_str(token_result.access_token().secret().clone().as_str());
-        self.wcl_token = auth_string;
-        self.wcl_token.clone()
-    }
-
-    pub fn query_character_metric(&self, spec: &UpdaterBaseDataClassSpec) -> Option<character_view::CharacterRankingMetricType> {
-        let metric_str: &str = &spec.metric.clone();
-        match metric_str {
-            "hps" => Some(character_view::CharacterRankingMetricType::hps),
-            _ => Some(character_view::CharacterRankingMetricType::dps)
-        }
-    }
-
-    pub fn query_character(&self, name: String, server_slug: String, server_region: String, zone_id: i64, class_id: i64) 
-        -> (Option<character_view::ResponseData>, Option<String>)
-    {
-        let mut vars = character_view::Variables {
-            name: name, server_slug: server_slug, server_region: server_region, 
-            zone_id: zone_id,
-            query_spec1: false, query_spec1_name: None, query_spec1_metric: None,
-            query_spec2: false, query_spec2_name: None, query_spec2_metric: None,
-            query_spec3: false, query_spec3_name: None, query_spec3_metric: None,
-            query_spec4: false, query_spec4_name: None, query_spec4_metric: None,
-            query_spec5: false, query_spec5_name: None, query_spec5_metric: None
-        };
-        if let Some(base_data_class) = self.base_data.classes.get(&class_id.to_string()) {
-            for spec_index in 1..=5 {
-                if let Some(spec_details) = base_data_class.specs.get(&spec_index.to_string()) {
-                    match spec_index {
-                        1 => {
-                            vars.query_spec1 = true;
-                            vars.query_spec1_name = Some(spec_details.slug.to_string());
-                            vars.query_spec1_metric = self.query_character_metric(spec_details);
-                        },
-                        2 => {
-                            vars.query_spec2 = true;
-                            vars.query_spec2_name = Some(spec_details.slug.to_string());
-                            vars.query_spec2_metric = self.query_character_metric(spec_details);
-                        },
-                        3 => {
-                            vars.query_spec3 = true;
-                            vars.query_spec3_name = Some(spec_details.slug.to_string());
-                            vars.query_spec3_metric = self.query_character_metric(spec_details);
-                        },
-                        4 => {
-                            vars.query_spec4 = true;
-                            vars.query_spec4_name = Some(spec_details.slug.to_string());
-                            vars.query_spec4_metric = self.query_character_metric(spec_details);
-                        },
-                        5 => {
-                            vars.query_spec5 = true;
-                            vars.query_spec5_name = Some(spec_details.slug.to_string());
-                            vars.query_spec5_metric = self.query_character_metric(spec_details);
-                        }
-                        _ => {}
+        valid_metric(&spec.metric).unwrap_or("dps")
+    }
+
+    pub fn character_query(&self, player: &UpdaterPlayer, zones: &[UpdaterZone]) -> Result<CharacterQuery, UpdateError> {
+        if zones.is_empty() {
+            return Err(UpdateError::Parse("No raid zone to rank, the zone list is missing or does not know the configured zones".to_string()));
+        }
+        let (region, server_slug) = self.realm_lookup(&player.realm)
+            .ok_or_else(|| UpdateError::UnknownRealm(player.realm.to_string()))?;
+        let mut query = CharacterQuery::new(&player.name, &server_slug, &region);
+        if self.config.recent_reports {
+            query = query.recent_reports();
+        }
+        if self.config.bracket_rankings {
+            query = query.brackets();
+        }
+        if self.config.timeframe != UpdaterTimeframe::Historical {
+            for timeframe in self.config.timeframe.query_timeframes() {
+                query = query.timeframe(timeframe);
+            }
+        }
+        for zone in zones.iter() {
+            query = query.zone(zone.id, zone.query_sizes(), zone.query_partitions(self.config.partition));
+            if self.config.encounter_rankings {
+                for encounter_id in zone.encounters.iter() {
+                    for (difficulty, zone_size) in zone.query_difficulties() {
+                        query = query.encounter(*encounter_id, difficulty, zone_size);
                     }
                 }
             }
-            let client = Client::builder()
-                .user_agent("graphql-rust/0.10.0")
-                .default_headers(
-                    std::iter::once((
-                        reqwest::header::AUTHORIZATION,
-                        reqwest::header::HeaderValue::from_str(self.wcl_token.as_str().clone()).unwrap()
-                    ))
-                    .collect()
-                )
-                .build().unwrap();
-            let vars_string = serde_json::to_string_pretty(&vars).unwrap();
-            let response_body = post_graphql::<CharacterView, _>(&client, "https://classic.warcraftlogs.com/api/v2/client", vars);
-            if let Err(e) = response_body {
-                warn!("Application error: {e}");
-                return (None, Some(vars_string));
-            }
-            (response_body.unwrap().data, Some(vars_string))
-        } else {
-            (None, None)
-        }
-    }
-
-    pub fn query_rate_limit(&self) -> Option<rate_limit_view::ResponseData> {
-        let vars = rate_limit_view::Variables {};
-        let client = Client::builder()
-            .user_agent("graphql-rust/0.10.0")
-            .default_headers(
-                std::iter::once((
-                    reqwest::header::AUTHORIZATION,
-                    reqwest::header::HeaderValue::from_str(self.wcl_token.as_str().clone()).unwrap()
-                ))
-                .collect()
-            )
-            .build().unwrap();
-        let response_body = post_graphql::<RateLimitView, _>(&client, "https://classic.warcraftlogs.com/api/v2/client", vars);
-        if let Err(e) = response_body {
-            warn!("Application error: {e}");
-            return None;
         }
-        response_body.unwrap().data
+        if let Some(base_data_class) = self.base_data_class(player.class) {
+            for (spec_index, spec_details) in base_data_class.query_specs() {
+                query = query.spec(spec_index, &spec_details.slug, self.query_character_metric(spec_details));
+            }
+        }
+        Ok(query)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, SystemTime};
+    use serde_json::json;
+    use crate::engine;
+    use crate::mock::{self, MockCharacter, MockGuild, MockWcl};
+    use super::{UpdateError, Updater, UpdaterBaseDataClassSpec, UpdaterBossRanking, UpdaterPartitionMode, UpdaterPlayerGuild, UpdaterSite, UpdaterTimeframe, COST_SAMPLES_MIN, POINTS_RESERVE, unix_now};
+
+    fn rankings() -> serde_json::Value {
+        json!({
+            "bestPerformanceAverage": 91.4,
+            "medianPerformanceAverage": 72.6,
+            "rankings": [
+                { "spec": "Arms", "rankPercent": 95.2, "medianPercent": 70.1 },
+                { "spec": null, "rankPercent": 0, "medianPercent": 0 }
+            ]
+        })
     }
 
-}
\ No newline at end of file
+    async fn updater(mock: &MockWcl, players: &[(&str, &str, i64)]) -> Arc<Mutex<Updater>> {
+        let mut updater = Updater::new();
+        updater.data_dir = mock::temp_dir("data");
+        updater.config.api_id = "mock-id".into();
+        updater.config.api_secret = "mock-secret".into();
+        updater.config.api_base_url = Some(mock.base_url().into());
+        updater.config.retry_base_ms = Some(1);
+        updater.config.zone_ids = vec![1017];
+        updater.config.game_dir = mock::game_dir(players).to_string_lossy().into();
+        updater.reset_api();
+        updater.read_addon_data();
+        let updater = Arc::new(Mutex::new(updater));
+        engine::update_zones(&updater).await.unwrap();
+        updater
+    }
+
+    /// Exports the player data and reads it back in
+    fn reload_addon_data(updater: &mut Updater) {
+        updater.write_addon_data();
+        updater.players.clear();
+        updater.read_addon_data();
+    }
+
+    async fn update_player(updater: &Arc<Mutex<Updater>>, realm: &str, name: &str) -> Result<(), UpdateError> {
+        let player = updater.lock().unwrap().get_player(realm, name).clone();
+        engine::update_player(updater, player).await
+    }
+
+    #[tokio::test]
+    async fn update_player_applies_rankings() {
+        let mock = MockWcl::start().await;
+        mock.character("Mockwarrior", "Mockrealm", 1, rankings());
+        let updater = updater(&mock, &[("Mockrealm", "Mockwarrior", 1)]).await;
+        assert_eq!(update_player(&updater, "Mockrealm", "Mockwarrior").await, Ok(()));
+        let updater = updater.lock().unwrap();
+        let player = &updater.players["Mockrealm"]["Mockwarrior"];
+        assert!(player.last_update_logs > 0);
+        let ranking = &player.ranking["1017-25"];
+        assert_eq!(ranking.allstar_ratings, vec![(1, 91, 73, "dps".into()), (2, 91, 73, "dps".into())]);
+        assert_eq!(ranking.encounter_ratings, vec![(1, 95, 70), (0, 0, 0)]);
+        assert_eq!((ranking.encounters, ranking.encounters_killed), (2, 1));
+        assert_eq!((player.level, player.faction.as_ref()), (25, "Horde"));
+    }
+
+    #[tokio::test]
+    async fn boss_rankings_are_exported() {
+        let mock = MockWcl::start().await;
+        mock.character("Mockwarrior", "Mockrealm", 1, rankings());
+        mock.state().characters.get_mut("Mockwarrior-mockrealm").unwrap().fields.insert("encounterRankings".to_string(), json!({
+            "totalKills": 3,
+            "medianPerformance": 64.2,
+            "ranks": [ { "rankPercent": 88.1 }, { "rankPercent": 97.6 } ]
+        }));
+        let updater = updater(&mock, &[("Mockrealm", "Mockwarrior", 1)]).await;
+        {
+            let mut updater = updater.lock().unwrap();
+            updater.config.encounter_rankings = true;
+            updater.config.metric_overrides.insert(2, "bossdps".into());
+            let player = updater.get_player("Mockrealm", "Mockwarrior").clone();
+            let document = updater.prepare_request(vec![player]).document;
+            assert!(document.contains("encounterRankings(encounterID: 745, difficulty: 4, size: 25, specName: \"Fury\", metric: bossdps)"));
+        }
+        assert_eq!(update_player(&updater, "Mockrealm", "Mockwarrior").await, Ok(()));
+        let mut updater = updater.lock().unwrap();
+        // Two bosses, each on normal 10/25 and the 25 player hard mode, for both specs
+        let boss_rankings = updater.players["Mockrealm"]["Mockwarrior"].boss_rankings.clone();
+        assert_eq!(boss_rankings.len(), 12);
+        assert!(boss_rankings.contains(&UpdaterBossRanking{ encounter_id: 745, difficulty: 4, size: 25, spec_id: 1, best: 98, median: 64, kills: 3, metric: "dps".into() }));
+        assert!(boss_rankings.contains(&UpdaterBossRanking{ encounter_id: 745, difficulty: 4, size: 25, spec_id: 2, best: 98, median: 64, kills: 3, metric: "bossdps".into() }));
+        reload_addon_data(&mut updater);
+        assert_eq!(updater.players["Mockrealm"]["Mockwarrior"].boss_rankings, boss_rankings);
+    }
+
+    #[tokio::test]
+    async fn partitions_are_ranked_separately() {
+        let mock = MockWcl::start().await;
+        mock.character("Mockwarrior", "Mockrealm", 1, rankings());
+        let updater = updater(&mock, &[("Mockrealm", "Mockwarrior", 1)]).await;
+        updater.lock().unwrap().config.partition = UpdaterPartitionMode::All;
+        assert_eq!(update_player(&updater, "Mockrealm", "Mockwarrior").await, Ok(()));
+        {
+            let updater = updater.lock().unwrap();
+            let ranking = &updater.players["Mockrealm"]["Mockwarrior"].ranking;
+            // The default partition keeps the plain key
+            assert!(ranking.contains_key("1017-25"));
+            assert!(ranking.contains_key("1017-25-p1"));
+            assert!(!ranking.contains_key("1017-25-p2"));
+        }
+        // Unknown partitions fall back to the default one
+        let mut updater_locked = updater.lock().unwrap();
+        let partitions = updater_locked.partition_list();
+        assert_eq!(partitions, vec![(1, "Phase 1".to_string()), (2, "Phase 2".to_string())]);
+        assert_eq!(UpdaterPartitionMode::Selected(1).label(&partitions), "Phase 1");
+        assert_eq!(UpdaterPartitionMode::Selected(7).label(&partitions), "Partition 7");
+        updater_locked.config.partition = UpdaterPartitionMode::Selected(7);
+        let player = updater_locked.get_player("Mockrealm", "Mockwarrior").clone();
+        let document = updater_locked.prepare_request(vec![player]).document;
+        assert!(!document.contains("partition:"));
+    }
+
+    #[tokio::test]
+    async fn both_timeframes_are_stored() {
+        let mock = MockWcl::start().await;
+        mock.character("Mockwarrior", "Mockrealm", 1, rankings());
+        let updater = updater(&mock, &[("Mockrealm", "Mockwarrior", 1)]).await;
+        updater.lock().unwrap().config.timeframe = UpdaterTimeframe::Both;
+        assert_eq!(update_player(&updater, "Mockrealm", "Mockwarrior").await, Ok(()));
+        let mut updater = updater.lock().unwrap();
+        let ranking = &updater.players["Mockrealm"]["Mockwarrior"].ranking;
+        assert!(ranking.contains_key("1017-25"));
+        assert_eq!(ranking["1017-25-today"].allstar_ratings, vec![(1, 91, 73, "dps".into()), (2, 91, 73, "dps".into())]);
+        reload_addon_data(&mut updater);
+        assert!(updater.players["Mockrealm"]["Mockwarrior"].ranking.contains_key("1017-10-today"));
+    }
+
+    #[tokio::test]
+    async fn metric_overrides_are_exported() {
+        let mock = MockWcl::start().await;
+        mock.character("Mockwarrior", "Mockrealm", 1, rankings());
+        let updater = updater(&mock, &[("Mockrealm", "Mockwarrior", 1)]).await;
+        {
+            let mut updater = updater.lock().unwrap();
+            updater.config.metric_overrides.insert(2, "bossdps".into());
+            updater.config.metric_overrides.insert(51, "notametric".into());
+            let player = updater.get_player("Mockrealm", "Mockwarrior").clone();
+            let document = updater.prepare_request(vec![player]).document;
+            assert!(document.contains("specName: \"Arms\", size: 25, metric: dps"));
+            assert!(document.contains("specName: \"Fury\", size: 25, metric: bossdps"));
+            let holy = UpdaterBaseDataClassSpec{ id: 51, metric: "hps".into(), ..Default::default() };
+            assert_eq!(updater.query_character_metric(&holy), "hps");
+        }
+        assert_eq!(update_player(&updater, "Mockrealm", "Mockwarrior").await, Ok(()));
+        let mut updater = updater.lock().unwrap();
+        let allstar_ratings = vec![(1, 91, 73, "dps".into()), (2, 91, 73, "bossdps".into())];
+        assert_eq!(updater.players["Mockrealm"]["Mockwarrior"].ranking["1017-25"].allstar_ratings, allstar_ratings);
+        reload_addon_data(&mut updater);
+        assert_eq!(updater.players["Mockrealm"]["Mockwarrior"].ranking["1017-25"].allstar_ratings, allstar_ratings);
+    }
+
+    #[tokio::test]
+    async fn bracket_rankings_are_exported() {
+        let mock = MockWcl::start().await;
+        mock.character("Mockwarrior", "Mockrealm", 1, rankings());
+        let updater = updater(&mock, &[("Mockrealm", "Mockwarrior", 1)]).await;
+        updater.lock().unwrap().config.bracket_rankings = true;
+        assert_eq!(update_player(&updater, "Mockrealm", "Mockwarrior").await, Ok(()));
+        let mut updater = updater.lock().unwrap();
+        assert_eq!(updater.players["Mockrealm"]["Mockwarrior"].ranking["1017-25"].bracket_ratings, vec![(1, 91, 73), (2, 91, 73)]);
+        reload_addon_data(&mut updater);
+        let ranking = &updater.players["Mockrealm"]["Mockwarrior"].ranking["1017-25"];
+        assert_eq!(ranking.bracket_ratings, vec![(1, 91, 73), (2, 91, 73)]);
+        assert_eq!(ranking.allstar_ratings.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn guild_is_exported() {
+        let mock = MockWcl::start().await;
+        mock.character("Mockwarrior", "Mockrealm", 1, rankings());
+        mock.state().characters.get_mut("Mockwarrior-mockrealm").unwrap().guild = Some((77, "The \"Mock\\Guild\"".to_string(), 3));
+        let updater = updater(&mock, &[("Mockrealm", "Mockwarrior", 1)]).await;
+        assert_eq!(update_player(&updater, "Mockrealm", "Mockwarrior").await, Ok(()));
+        let mut updater = updater.lock().unwrap();
+        let guild = UpdaterPlayerGuild{ id: 77, name: "The \"Mock\\Guild\"".into(), realm: "Mockrealm".into(), rank: 3 };
+        assert_eq!(updater.players["Mockrealm"]["Mockwarrior"].guild, Some(guild.clone()));
+        reload_addon_data(&mut updater);
+        assert_eq!(updater.players["Mockrealm"]["Mockwarrior"].guild, Some(guild));
+    }
+
+    #[tokio::test]
+    async fn guild_progress_is_exported() {
+        let mock = MockWcl::start().await;
+        mock.character("Mockwarrior", "Mockrealm", 1, rankings());
+        mock.state().characters.get_mut("Mockwarrior-mockrealm").unwrap().guild = Some((77, "Mock Guild".to_string(), 3));
+        mock.state().guilds.insert(77, MockGuild{
+            name: "Mock Guild".to_string(), server: "Mockrealm".to_string(),
+            progress: json!({ "worldRank": { "number": 120 }, "regionRank": { "number": 40 }, "serverRank": { "number": 2 } }),
+            ..Default::default()
+        });
+        let updater = updater(&mock, &[("Mockrealm", "Mockwarrior", 1)]).await;
+        updater.lock().unwrap().config.guild_progress = true;
+        assert_eq!(update_player(&updater, "Mockrealm", "Mockwarrior").await, Ok(()));
+        // Failed requests are retried instead of waiting for the refresh interval
+        mock.state().graphql_errors.push("Service unavailable".to_string());
+        assert!(matches!(engine::update_guilds(&updater).await, Err(UpdateError::Parse(_))));
+        assert_eq!(updater.lock().unwrap().cache.guilds.get(&77).map(|guild| guild.updated).unwrap_or_default(), 0);
+        mock.state().graphql_errors.clear();
+        assert_eq!(engine::update_guilds(&updater).await, Ok(()));
+        let graphql_requests = mock.state().graphql_requests;
+        // Cached until the refresh interval passed
+        assert_eq!(engine::update_guilds(&updater).await, Ok(()));
+        assert_eq!(mock.state().graphql_requests, graphql_requests);
+        let mut updater = updater.lock().unwrap();
+        assert_eq!(updater.cache.guilds[&77].ranks["1017-25"], (120, 40, 2));
+        updater.write_addon_data();
+        let mut addon_data = std::path::PathBuf::from(updater.config.game_dir.as_ref());
+        addon_data.push("Interface/AddOns/LogTracker/AppData.lua");
+        let lua = mlua::Lua::new();
+        lua.load(&std::fs::read_to_string(addon_data).unwrap()).exec().unwrap();
+        let world_rank: i64 = lua.load(r#"LogTracker_AppGuildData["Mockrealm"]["Mock Guild"]["1017-10"][1]"#).eval().unwrap();
+        assert_eq!(world_rank, 120);
+    }
+
+    #[tokio::test]
+    async fn inactive_characters_are_updated_slowly() {
+        let mock = MockWcl::start().await;
+        mock.character("Mockwarrior", "Mockrealm", 1, rankings());
+        let now = unix_now();
+        let last_raided = now - 86400 * 60;
+        mock.state().characters.get_mut("Mockwarrior-mockrealm").unwrap().fields.insert("recentReports".to_string(), json!({
+            "data": [ { "startTime": (last_raided * 1000) as f64 } ]
+        }));
+        let updater = updater(&mock, &[("Mockrealm", "Mockwarrior", 1)]).await;
+        updater.lock().unwrap().config.recent_reports = true;
+        assert_eq!(update_player(&updater, "Mockrealm", "Mockwarrior").await, Ok(()));
+        let mut updater = updater.lock().unwrap();
+        assert_eq!(updater.players["Mockrealm"]["Mockwarrior"].last_raided, last_raided);
+        reload_addon_data(&mut updater);
+        let player = updater.get_player("Mockrealm", "Mockwarrior");
+        assert_eq!(player.last_raided, last_raided);
+        // Seen recently and not updated for 3 days, but without a report for 2 months
+        player.level = 80;
+        player.last_update = now;
+        player.last_update_logs = now - 86400 * 3;
+        updater.rewrite_update_queue();
+        assert!(updater.update_queue.is_empty());
+        updater.get_player("Mockrealm", "Mockwarrior").last_raided = now - 86400;
+        updater.rewrite_update_queue();
+        assert_eq!(updater.update_queue.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn level_cap_depends_on_site() {
+        let mock = MockWcl::start().await;
+        let updater = updater(&mock, &[("Mockrealm", "Mockwarrior", 1), ("Mockrealm", "Mockrogue", 4)]).await;
+        let mut updater = updater.lock().unwrap();
+        updater.config.site = UpdaterSite::Era;
+        updater.get_player("Mockrealm", "Mockwarrior").level = 60;
+        updater.get_player("Mockrealm", "Mockrogue").level = 59;
+        updater.rewrite_update_queue();
+        assert_eq!(updater.update_queue.len(), 1);
+        assert_eq!(updater.update_queue[0].name.as_ref(), "Mockwarrior");
+        // The same character is below the level cap of the other sites
+        updater.config.site = UpdaterSite::Classic;
+        updater.rewrite_update_queue();
+        assert!(updater.update_queue.is_empty());
+    }
+
+    #[tokio::test]
+    async fn sites_keep_their_game_dir() {
+        let mock = MockWcl::start().await;
+        let updater = updater(&mock, &[("Mockrealm", "Mockwarrior", 1)]).await;
+        let mut updater = updater.lock().unwrap();
+        let game_dir = updater.config.game_dir.clone();
+        updater.set_site(UpdaterSite::Era);
+        assert!(updater.config.game_dir.is_empty());
+        assert!(updater.players.is_empty());
+        // Nothing to export without a game directory
+        updater.write_addon_data();
+        updater.set_site(UpdaterSite::Classic);
+        assert_eq!(updater.config.game_dir, game_dir);
+        assert!(updater.players["Mockrealm"].contains_key("Mockwarrior"));
+    }
+
+    #[tokio::test]
+    async fn guild_members_are_queued() {
+        let mock = MockWcl::start().await;
+        let members: Vec<(String, i64)> = (0..150).map(|member_index| (format!("Mockmember{}", member_index), 5)).collect();
+        mock.state().guilds.insert(77, MockGuild{ name: "Mock Guild".to_string(), server: "Mockrealm".to_string(), members, ..Default::default() });
+        let updater = updater(&mock, &[("Mockrealm", "Mockwarrior", 1)]).await;
+        updater.lock().unwrap().rewrite_update_queue();
+        assert_eq!(engine::queue_guild(&updater, "Mockrealm", "Mock Guild", 2).await, Ok(150));
+        {
+            let updater = updater.lock().unwrap();
+            assert_eq!(updater.update_queue.len(), 151);
+            assert_eq!(updater.update_queue[0].update_priority, 6);
+            assert_eq!(updater.players["Mockrealm"]["Mockmember149"].class, 5);
+        }
+        // Queueing again replaces the pending entries
+        assert_eq!(engine::queue_guild(&updater, "Mockrealm", "Mock Guild", 0).await, Ok(150));
+        assert_eq!(updater.lock().unwrap().update_queue.len(), 151);
+        assert_eq!(
+            engine::queue_guild(&updater, "Mockrealm", "Nobody", 0).await,
+            Err(UpdateError::GuildNotFound("Nobody-Mockrealm".to_string()))
+        );
+        assert_eq!(
+            engine::queue_guild(&updater, "Otherrealm", "Mock Guild", 0).await,
+            Err(UpdateError::UnknownRealm("Otherrealm".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn guild_realms_are_resolved() {
+        let mock = MockWcl::start().await;
+        let members: Vec<(String, i64)> = (0..3).map(|member_index| (format!("Mockmember{}", member_index), 5)).collect();
+        mock.state().guilds.insert(77, MockGuild{ name: "Mock Guild".to_string(), server: "Pyrewood Village".to_string(), members, ..Default::default() });
+        mock.state().servers.push(("EU".to_string(), "Pyrewood Village".to_string(), "pyrewood-village".to_string()));
+        let updater = updater(&mock, &[("Mockrealm", "Mockwarrior", 1)]).await;
+        assert_eq!(engine::queue_guild(&updater, "Pyrewood Village", "Mock Guild", 0).await, Ok(3));
+        let updater = updater.lock().unwrap();
+        assert_eq!(updater.realm_lookup("Pyrewood Village"), Some(("EU".to_string(), "pyrewood-village".to_string())));
+        assert_eq!(updater.players["Pyrewood Village"]["Mockmember2"].class, 5);
+    }
+
+    #[tokio::test]
+    async fn renamed_characters_are_merged() {
+        let mock = MockWcl::start().await;
+        mock.character("Oldname", "Mockrealm", 1, rankings());
+        let updater = updater(&mock, &[("Mockrealm", "Oldname", 1)]).await;
+        updater.lock().unwrap().config.encounter_rankings = true;
+        mock.state().characters.get_mut("Oldname-mockrealm").unwrap().fields.insert("encounterRankings".to_string(), json!({
+            "totalKills": 3, "medianPerformance": 64.2, "ranks": [ { "rankPercent": 88.1 } ]
+        }));
+        assert_eq!(update_player(&updater, "Mockrealm", "Oldname").await, Ok(()));
+        updater.lock().unwrap().write_addon_data();
+        // Renamed and transferred, the API reports the same canonical id under the new name
+        let character = mock.state().characters.remove("Oldname-mockrealm").unwrap();
+        mock.state().characters.insert("Newname-otherrealm".to_string(), MockCharacter{ fields: Default::default(), ..character });
+        updater.lock().unwrap().base_data.region_by_server_name.insert("Otherrealm".to_string(), "EU".to_string());
+        updater.lock().unwrap().config.encounter_rankings = false;
+        assert_eq!(update_player(&updater, "Otherrealm", "Newname").await, Ok(()));
+        let mut updater = updater.lock().unwrap();
+        assert!(!updater.players["Mockrealm"].contains_key("Oldname"));
+        assert_eq!(updater.players["Otherrealm"]["Newname"].boss_rankings.len(), 12);
+        // The previous entry stays dropped, even though the saved variables still know it
+        reload_addon_data(&mut updater);
+        assert!(!updater.players.get("Mockrealm").is_some_and(|player_list| player_list.contains_key("Oldname")));
+        assert_eq!(updater.players["Otherrealm"]["Newname"].canonical_id, 1000);
+        // Another character taking the previous name is imported again
+        let mut saved_path = std::path::PathBuf::from(updater.config.game_dir.as_ref());
+        saved_path.push("WTF/Account/MOCK/SavedVariables/LogTracker.lua");
+        std::fs::write(saved_path, format!(
+            "LogTrackerDB = {{ playerData = {{ [\"Mockrealm\"] = {{ [\"Oldname\"] = {{ lastUpdate = {}, class = 4, level = 80 }} }} }} }}", unix_now() + 1
+        )).unwrap();
+        updater.players.clear();
+        updater.read_addon_data();
+        assert_eq!(updater.players["Mockrealm"]["Oldname"].class, 4);
+    }
+
+    #[tokio::test]
+    async fn unknown_realms_are_resolved() {
+        let mock = MockWcl::start().await;
+        mock.character("Mockwarrior", "Pyrewood Village", 1, rankings());
+        mock.character("Mockpriest", "Mock's Realm", 5, rankings());
+        {
+            let mut state = mock.state();
+            // The slug differs from the one derived from the name
+            let character = state.characters.remove("Mockpriest-mocks-realm").unwrap();
+            state.characters.insert("Mockpriest-mocks-realm-2".to_string(), character);
+            state.servers.push(("US".to_string(), "Pyrewood Village".to_string(), "pyrewood-village".to_string()));
+            state.servers.push(("EU".to_string(), "Pyrewood Village".to_string(), "pyrewood-village".to_string()));
+            for server_index in 0..150 {
+                state.servers.push(("EU".to_string(), format!("Realm {}", server_index), format!("realm-{}", server_index)));
+            }
+            state.servers.push(("EU".to_string(), "Mock's Realm".to_string(), "mocks-realm-2".to_string()));
+        }
+        let updater = updater(&mock, &[("Mockrealm", "Mockwarrior", 1)]).await;
+        // Found by the slug guessed from the name
+        assert_eq!(update_player(&updater, "Pyrewood Village", "Mockwarrior").await, Ok(()));
+        // Found in the second page of the server lists
+        assert_eq!(update_player(&updater, "Mock's Realm", "Mockpriest").await, Ok(()));
+        assert_eq!(
+            update_player(&updater, "Nowhere", "Mockwarrior").await,
+            Err(UpdateError::UnknownRealm("Nowhere".to_string()))
+        );
+        let graphql_requests = mock.state().graphql_requests;
+        let mut updater = updater.lock().unwrap();
+        assert_eq!(updater.realm_lookup("Mock's Realm"), Some(("EU".to_string(), "mocks-realm-2".to_string())));
+        assert!(updater.cache.realms["Nowhere"].region.is_empty());
+        // Lookups are cached and failed realms are not retried right away
+        updater.cache = Default::default();
+        updater.load_cache();
+        assert_eq!(updater.realm_lookup("Pyrewood Village"), Some(("US".to_string(), "pyrewood-village".to_string())));
+        updater.get_player("Nowhere", "Mockwarrior").class = 1;
+        updater.rewrite_update_queue();
+        assert_eq!(updater.next_unresolved_realm(), None);
+        assert_eq!(mock.state().graphql_requests, graphql_requests);
+    }
+
+    #[tokio::test]
+    async fn every_spec_is_queried() {
+        let mock = MockWcl::start().await;
+        mock.character("Mockwarrior", "Mockrealm", 1, rankings());
+        let updater = updater(&mock, &[("Mockrealm", "Mockwarrior", 1)]).await;
+        {
+            // More specs than the base data of the current game versions has
+            let mut updater = updater.lock().unwrap();
+            let warrior = updater.base_data.classes.get_mut("1").unwrap();
+            for spec_index in 3..=7 {
+                let spec_details = UpdaterBaseDataClassSpec{ id: spec_index, slug: format!("Spec{}", spec_index).into(), metric: "dps".into(), ..Default::default() };
+                warrior.specs.insert(spec_index.to_string(), spec_details);
+            }
+            let player = updater.get_player("Mockrealm", "Mockwarrior").clone();
+            let request = updater.prepare_request(vec![player]);
+            // Sizes 10 and 25 for each of the seven specs
+            assert_eq!(request.document.matches("zoneRankings(").count(), 14);
+            assert!(request.document.contains("zoneRankings1017Size25Spec7: zoneRankings(zoneID: 1017, specName: \"Spec7\""));
+        }
+        assert_eq!(update_player(&updater, "Mockrealm", "Mockwarrior").await, Ok(()));
+        let updater = updater.lock().unwrap();
+        let spec_ids: Vec<i64> = updater.players["Mockrealm"]["Mockwarrior"].ranking["1017-25"].allstar_ratings.iter().map(|allstar| allstar.0).collect();
+        assert_eq!(spec_ids, vec![1, 2, 3, 4, 5, 6, 7]);
+    }
+
+    #[tokio::test]
+    async fn classes_are_fetched_without_base_data() {
+        let mock = MockWcl::start().await;
+        mock.character("Mockpriest", "Mockrealm", 5, rankings());
+        let updater = updater(&mock, &[("Mockrealm", "Mockpriest", 5)]).await;
+        // The base data addon is not installed
+        let base_data_classes = std::mem::take(&mut updater.lock().unwrap().base_data.classes);
+        assert_eq!(update_player(&updater, "Mockrealm", "Mockpriest").await, Ok(()));
+        {
+            let updater = updater.lock().unwrap();
+            let allstar_ratings = &updater.players["Mockrealm"]["Mockpriest"].ranking["1017-25"].allstar_ratings;
+            assert_eq!(allstar_ratings, &vec![(256, 91, 73, "hps".into()), (257, 91, 73, "hps".into()), (258, 91, 73, "dps".into())]);
+            assert!(!updater.is_classes_update_due());
+        }
+        // Cached for the next start, the addon values take precedence once it is installed
+        let mut updater = updater.lock().unwrap();
+        updater.cache = Default::default();
+        updater.load_cache();
+        assert_eq!(updater.base_data_class(5).unwrap().specs.len(), 3);
+        updater.base_data.classes = base_data_classes;
+        assert_eq!(updater.base_data_class(5).unwrap().specs["1"].id, 51);
+    }
+
+    #[tokio::test]
+    async fn hidden_characters_are_skipped() {
+        let mock = MockWcl::start().await;
+        mock.character("Mockwarrior", "Mockrealm", 1, rankings());
+        mock.state().characters.get_mut("Mockwarrior-mockrealm").unwrap().hidden = true;
+        let updater = updater(&mock, &[("Mockrealm", "Mockwarrior", 1)]).await;
+        assert_eq!(update_player(&updater, "Mockrealm", "Mockwarrior").await, Ok(()));
+        let mut updater = updater.lock().unwrap();
+        assert!(updater.players["Mockrealm"]["Mockwarrior"].hidden);
+        assert!(updater.players["Mockrealm"]["Mockwarrior"].ranking.is_empty());
+        updater.rewrite_update_queue();
+        assert!(updater.update_queue.is_empty());
+        // The hidden status survives a reload of the exported data
+        reload_addon_data(&mut updater);
+        assert!(updater.players["Mockrealm"]["Mockwarrior"].hidden);
+    }
+
+    #[tokio::test]
+    async fn update_player_reports_missing_character() {
+        let mock = MockWcl::start().await;
+        let updater = updater(&mock, &[("Mockrealm", "Nobody", 1)]).await;
+        assert_eq!(
+            update_player(&updater, "Mockrealm", "Nobody").await,
+            Err(UpdateError::CharacterNotFound("Nobody-Mockrealm".to_string()))
+        );
+        // Not found characters are not requested again right away
+        assert!(updater.lock().unwrap().players["Mockrealm"]["Nobody"].last_update_logs > 0);
+    }
+
+    #[tokio::test]
+    async fn update_player_reports_graphql_errors() {
+        let mock = MockWcl::start().await;
+        mock.character("Mockwarrior", "Mockrealm", 1, rankings());
+        let updater = updater(&mock, &[("Mockrealm", "Mockwarrior", 1)]).await;
+        mock.state().graphql_errors.push("Invalid zone".to_string());
+        assert!(matches!(update_player(&updater, "Mockrealm", "Mockwarrior").await, Err(UpdateError::Parse(message)) if message.contains("Invalid zone")));
+    }
+
+    #[tokio::test]
+    async fn auth_failure_is_reported() {
+        let mock = MockWcl::start().await;
+        mock.state().reject_auth = true;
+        let mut updater = Updater::new();
+        updater.data_dir = mock::temp_dir("data");
+        updater.config.api_base_url = Some(mock.base_url().into());
+        updater.reset_api();
+        let updater = Arc::new(Mutex::new(updater));
+        assert!(matches!(engine::update_api_limit(&updater).await, Err(UpdateError::Auth(_))));
+        assert!(!updater.lock().unwrap().auth_error().is_empty());
+        // Recovers once the credentials are accepted
+        mock.state().reject_auth = false;
+        assert_eq!(engine::update_api_limit(&updater).await, Ok(()));
+        assert!(updater.lock().unwrap().auth_error().is_empty());
+    }
+
+    #[tokio::test]
+    async fn token_network_errors_are_retried() {
+        let mock = MockWcl::start().await;
+        let updater = updater(&mock, &[]).await;
+        mock.state().token_requests = 0;
+        updater.lock().unwrap().reset_api();
+        mock.state().token_drops = 2;
+        assert_eq!(engine::update_api_limit(&updater).await, Ok(()));
+        assert_eq!(mock.state().token_requests, 3);
+        // Gives up after the configured number of retries, without blaming the credentials
+        updater.lock().unwrap().reset_api();
+        mock.state().token_drops = 10;
+        assert!(matches!(engine::update_api_limit(&updater).await, Err(UpdateError::Network(_))));
+        assert!(updater.lock().unwrap().auth_error().is_empty());
+    }
+
+    #[tokio::test]
+    async fn revoked_token_is_renewed() {
+        let mock = MockWcl::start().await;
+        mock.character("Mockwarrior", "Mockrealm", 1, rankings());
+        let updater = updater(&mock, &[("Mockrealm", "Mockwarrior", 1)]).await;
+        assert_eq!(update_player(&updater, "Mockrealm", "Mockwarrior").await, Ok(()));
+        mock.state().token_generation += 1;
+        assert_eq!(update_player(&updater, "Mockrealm", "Mockwarrior").await, Ok(()));
+        assert_eq!(mock.state().token_requests, 2);
+    }
+
+    #[tokio::test]
+    async fn update_api_limit_reads_points() {
+        let mock = MockWcl::start().await;
+        mock.state().points_spent = 120.0;
+        let updater = updater(&mock, &[]).await;
+        assert_eq!(engine::update_api_limit(&updater).await, Ok(()));
+        let updater = updater.lock().unwrap();
+        assert_eq!(updater.points_used(), 120.0);
+        assert_eq!(updater.wcl_points_limit, 3600.0);
+        assert!(updater.wcl_reset_at > SystemTime::now() + Duration::new(3600, 0));
+    }
+
+    #[tokio::test]
+    async fn exhausted_rate_limit_is_reported() {
+        let mock = MockWcl::start().await;
+        mock.character("Mockwarrior", "Mockrealm", 1, rankings());
+        mock.state().points_spent = 3600.0;
+        let updater = updater(&mock, &[("Mockrealm", "Mockwarrior", 1)]).await;
+        assert_eq!(update_player(&updater, "Mockrealm", "Mockwarrior").await, Err(UpdateError::RateLimited(None)));
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried() {
+        let mock = MockWcl::start().await;
+        mock.character("Mockwarrior", "Mockrealm", 1, rankings());
+        let updater = updater(&mock, &[("Mockrealm", "Mockwarrior", 1)]).await;
+        // Learn the query cost first, so only the character requests hit the failing endpoint
+        assert_eq!(update_player(&updater, "Mockrealm", "Mockwarrior").await, Ok(()));
+        for cost in updater.lock().unwrap().cache.query_costs.values_mut() {
+            cost.samples = COST_SAMPLES_MIN;
+        }
+        mock.state().statuses.extend([502, 503]);
+        assert_eq!(update_player(&updater, "Mockrealm", "Mockwarrior").await, Ok(()));
+        // Gives up after the configured number of retries
+        mock.state().statuses.extend([500, 500, 500, 500]);
+        assert!(matches!(update_player(&updater, "Mockrealm", "Mockwarrior").await, Err(UpdateError::Network(_))));
+        // Client errors are not retried
+        mock.state().statuses.extend([400, 400]);
+        assert!(matches!(update_player(&updater, "Mockrealm", "Mockwarrior").await, Err(UpdateError::Rejected(_))));
+        assert_eq!(mock.state().statuses.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn prepare_next_reserves_points() {
+        let mock = MockWcl::start().await;
+        let updater = updater(&mock, &[("Mockrealm", "Mockwarrior", 1)]).await;
+        let mut updater = updater.lock().unwrap();
+        updater.wcl_points_limit = 3600.0;
+        updater.wcl_points_used = 3100.0;
+        updater.wcl_reset_at = SystemTime::now() + Duration::new(3600, 0);
+        assert!(matches!(updater.prepare_next(), Err(UpdateError::RateLimited(Some(_)))));
+        assert_eq!(updater.update_queue_pos, 0);
+    }
+
+    #[tokio::test]
+    async fn sampled_requests_are_counted_once() {
+        let mock = MockWcl::start().await;
+        mock.character("Mockwarrior", "Mockrealm", 1, rankings());
+        let updater = updater(&mock, &[("Mockrealm", "Mockwarrior", 1)]).await;
+        // The second request has a cost estimate, but is still sampled
+        assert_eq!(update_player(&updater, "Mockrealm", "Mockwarrior").await, Ok(()));
+        assert_eq!(update_player(&updater, "Mockrealm", "Mockwarrior").await, Ok(()));
+        assert_eq!(updater.lock().unwrap().points_used(), mock.state().points_spent);
+    }
+
+    #[tokio::test]
+    async fn forecast_keeps_points_reserve() {
+        let mock = MockWcl::start().await;
+        let updater = updater(&mock, &[("Mockrealm", "Mockwarrior", 1), ("Mockrealm", "Mockrogue", 4), ("Otherrealm", "Mockwarrior", 1)]).await;
+        let mut updater = updater.lock().unwrap();
+        let zones = updater.selected_zones();
+        for player in updater.update_queue.clone() {
+            let query = updater.character_query(&player, &zones).unwrap();
+            updater.record_query_cost(&Updater::query_cost_key(&query), 10.0);
+        }
+        updater.wcl_points_limit = 3600.0;
+        updater.wcl_points_used = 3600.0 - POINTS_RESERVE - 25.0;
+        assert_eq!(updater.forecast_players(), Some(2));
+        updater.wcl_points_used = 3600.0 - POINTS_RESERVE - 5.0;
+        assert_eq!(updater.forecast_players(), Some(0));
+        updater.wcl_points_used = 0.0;
+        assert_eq!(updater.forecast_players(), Some(3));
+    }
+
+    #[tokio::test]
+    async fn sizes_are_taken_from_the_zone() {
+        let mock = MockWcl::start().await;
+        mock.character("Mockwarrior", "Mockrealm", 1, rankings());
+        mock.state().zones["worldData"]["expansions"][0]["zones"].as_array_mut().unwrap().push(json!(
+            { "id": 1005, "name": "Molten Core", "frozen": true, "difficulties": [ { "id": 3, "name": "Normal", "sizes": [40] } ] }
+        ));
+        let updater = updater(&mock, &[("Mockrealm", "Mockwarrior", 1)]).await;
+        {
+            let mut updater = updater.lock().unwrap();
+            updater.config.zone_ids = vec![1005];
+            let player = updater.get_player("Mockrealm", "Mockwarrior").clone();
+            let document = updater.prepare_request(vec![player]).document;
+            assert!(document.contains("zoneRankings1005Size40"));
+            assert!(!document.contains("Size25"));
+        }
+        assert_eq!(update_player(&updater, "Mockrealm", "Mockwarrior").await, Ok(()));
+        let mut updater = updater.lock().unwrap();
+        assert_eq!(updater.players["Mockrealm"]["Mockwarrior"].ranking["1005-40"].allstar_ratings, vec![(1, 91, 73, "dps".into()), (2, 91, 73, "dps".into())]);
+        reload_addon_data(&mut updater);
+        assert!(updater.players["Mockrealm"]["Mockwarrior"].ranking.contains_key("1005-40"));
+        // Zones missing from the zone list can't be ranked
+        updater.config.zone_ids = vec![9999];
+        let player = updater.get_player("Mockrealm", "Mockwarrior").clone();
+        assert!(matches!(updater.prepare_request(vec![player]).results[0], Err(UpdateError::Parse(_))));
+    }
+
+    #[tokio::test]
+    async fn engine_drains_queue() {
+        let mock = MockWcl::start().await;
+        let players = [("Mockrealm", "Mockwarrior", 1), ("Mockrealm", "Mockpriest", 5), ("Otherrealm", "Mockwarrior", 1)];
+        for (realm, name, class) in players {
+            mock.character(name, realm, class, rankings());
+        }
+        let updater = updater(&mock, &players).await;
+        assert_eq!(updater.lock().unwrap().update_queue.len(), 3);
+        let engine_task = tokio::spawn(engine::run(updater.clone()));
+        for _ in 0..100 {
+            {
+                let updater = updater.lock().unwrap();
+                if (updater.update_queue_pos == updater.update_queue.len()) && (updater.requests_in_flight() == 0) {
+                    break;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        updater.lock().unwrap().stop();
+        engine_task.await.unwrap();
+        let updater = updater.lock().unwrap();
+        for (realm, name, _class) in players {
+            assert!(updater.players[realm][name].last_update_logs > 0, "{}-{} was not updated", name, realm);
+        }
+        // The first request was used to learn the query cost
+        assert!(!updater.cache.query_costs.is_empty());
+        let mut app_data = std::path::PathBuf::from(updater.config.game_dir.to_string());
+        app_data.push("Interface/AddOns/LogTracker/AppData.lua");
+        let app_data = std::fs::read_to_string(app_data).unwrap();
+        assert!(app_data.contains("[\"Mockpriest\"]"));
+    }
+}